@@ -1,4 +1,7 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/api.proto")?;
+    // 数据帧的载荷解码为Bytes，避免额外拷贝
+    let mut config = prost_build::Config::new();
+    config.bytes([".api.TransferBody.resp_data", ".api.TransferReply.req_data"]);
+    tonic_build::configure().compile_with_config(config, &["proto/api.proto"], &["proto"])?;
     Ok(())
 }
@@ -1,10 +1,18 @@
 use std::error::Error;
+use std::time::Duration;
 use anyhow::anyhow;
 use clap::{Parser, Subcommand};
 use env_logger::Env;
+use log::warn;
 use rslocal::client;
-use rslocal::client::{ClientError};
+use rslocal::client::{ClientError, TlsOptions};
+use rslocal::client::oidc::{self, OidcSession};
 use rslocal::server::api::Protocol;
+use tokio::time::sleep;
+use tonic::Code;
+
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
 
 /// A fictional versioning CLI
 #[derive(Debug, Parser)]
@@ -28,6 +36,21 @@ enum Commands {
     /// config the client
     Config {},
 
+    /// log in to an OIDC enabled server with the device authorization flow
+    Login {
+        /// The issuer url of the identity provider
+        #[clap(long)]
+        issuer: Option<String>,
+        /// The OAuth client id registered for rslocal
+        #[clap(long)]
+        client_id: Option<String>,
+        #[clap(long, default_value_t = String::from(oidc::DEFAULT_SCOPE))]
+        scope: String,
+    },
+
+    /// remove the cached OIDC credentials
+    Logout {},
+
     /// start an HTTP tunnel
     #[clap(arg_required_else_help = true)]
     Http {
@@ -51,29 +74,116 @@ async fn main() -> anyhow::Result<()> {
         return client::config::setup();
     }
 
-    let cfg = client::config::load(&args.config)?;
     let env = Env::default().default_filter_or(format!("rslocal={}", &args.log_level));
     env_logger::Builder::from_env(env).init();
+    if let Commands::Login { issuer, client_id, scope } = &args.command {
+        // 首次登录时配置文件可能还不存在
+        let cfg = client::config::load(&args.config).ok();
+        let saved = |key: &str| cfg.as_ref().and_then(|cfg| cfg.get_string(key).ok());
+        let issuer = issuer.clone().or_else(|| saved("oidc_issuer")).ok_or_else(|| anyhow!("--issuer is required"))?;
+        let client_id = client_id.clone().or_else(|| saved("oidc_client_id")).ok_or_else(|| anyhow!("--client-id is required"))?;
+        return oidc::login(&issuer, &client_id, scope).await;
+    }
+
+    let cfg = client::config::load(&args.config)?;
+    if let Commands::Logout {} = args.command {
+        return oidc::logout(&cfg).await;
+    }
 
     let endpoint = cfg.get_string("endpoint").unwrap();
-    let token = cfg.get_string("token").unwrap();
+    // 通过`rslocal login`登录后优先使用OIDC令牌
+    let mut creds = match OidcSession::from_config(&cfg) {
+        Some(session) => Credentials::Oidc(session),
+        None => Credentials::Token(cfg.get_string("token").unwrap_or_default()),
+    };
+    let tls = TlsOptions {
+        ca_cert: cfg.get_string("ca_cert").ok(),
+        client_cert: cfg.get_string("client_cert").ok(),
+        client_key: cfg.get_string("client_key").ok(),
+    };
     match args.command {
         Commands::Http { port, subdomain } => {
             let sd = subdomain.unwrap_or_default();
             let target = format!("127.0.0.1:{}", port);
-            build_tunnel(endpoint, token, Protocol::Http, target, sd).await
+            build_tunnel(endpoint, &mut creds, tls, Protocol::Http, target, sd).await
         }
         Commands::Tcp { port } => {
             let target = format!("127.0.0.1:{}", port);
-            build_tunnel(endpoint, token, Protocol::Tcp, target, String::default()).await
+            build_tunnel(endpoint, &mut creds, tls, Protocol::Tcp, target, String::default()).await
         }
         _ => { Ok(()) }
     }
 }
 
-async fn build_tunnel(endpoint: String, token: String, protocol: Protocol, target: String, subdomain: String) -> anyhow::Result<()> {
-    let mut tunnel = wrapper(client::Tunnel::connect(endpoint.as_str(), token.as_str()).await)?;
-    wrapper(tunnel.start(protocol, target, subdomain.as_str()).await)
+enum Credentials {
+    Token(String),
+    Oidc(OidcSession),
+}
+
+impl Credentials {
+    async fn token(&mut self) -> anyhow::Result<String> {
+        match self {
+            Credentials::Token(token) => Ok(token.clone()),
+            Credentials::Oidc(session) => session.token().await,
+        }
+    }
+}
+
+async fn build_tunnel(endpoint: String, creds: &mut Credentials, tls: TlsOptions, protocol: Protocol, target: String, subdomain: String) -> anyhow::Result<()> {
+    let token = creds.token().await?;
+    let mut tunnel = wrapper(client::Tunnel::connect(endpoint.as_str(), token.as_str(), &tls).await)?;
+    let mut reconnected = false;
+    loop {
+        // 退出前注销会话，服务端立即释放隧道
+        let result = tokio::select! {
+            result = tunnel.start(protocol, target.clone(), subdomain.as_str()) => result,
+            _ = tokio::signal::ctrl_c() => {
+                if let Err(err) = tunnel.logout().await {
+                    warn!("logout failed: {}", err);
+                }
+                return Ok(());
+            }
+        };
+
+        // 首次建立隧道失败时直接退出，断线重连时服务端可能还没释放旧隧道
+        let err = match result {
+            Err(ClientError::Disconnect(err)) => ClientError::Disconnect(err),
+            Err(err) if reconnected && should_reconnect(&err) => err,
+            result => return wrapper(result),
+        };
+        reconnected = true;
+
+        // 断线后按指数退避重连，直到服务端恢复
+        let mut delay = RECONNECT_MIN_DELAY;
+        warn!("tunnel lost: {}, reconnecting in {:?}", err, delay);
+        tunnel = loop {
+            sleep(delay).await;
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+            // 长时间运行后令牌可能已过期，重连前刷新；IdP暂时不可达时继续重试
+            let token = match creds.token().await {
+                Ok(token) => token,
+                Err(err) if err.is::<reqwest::Error>() => {
+                    warn!("token refresh failed: {}, retrying in {:?}", err, delay);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            match client::Tunnel::connect(endpoint.as_str(), token.as_str(), &tls).await {
+                Ok(tunnel) => break tunnel,
+                Err(err) if should_reconnect(&err) => warn!("reconnect failed: {}, retrying in {:?}", err, delay),
+                Err(err) => return wrapper(Err(err)),
+            }
+        };
+    }
+}
+
+// 服务端暂时不可用、登录受限或旧隧道尚未释放时可以重试，其余错误直接退出
+fn should_reconnect(err: &ClientError) -> bool {
+    match err {
+        ClientError::Connect(_) | ClientError::Disconnect(_) => true,
+        ClientError::Status(status) => matches!(status.code(), Code::Unavailable | Code::ResourceExhausted | Code::AlreadyExists | Code::Unknown),
+        _ => false,
+    }
 }
 
 fn wrapper<T>(result: Result<T, ClientError>) -> anyhow::Result<T> {
@@ -82,9 +192,9 @@ fn wrapper<T>(result: Result<T, ClientError>) -> anyhow::Result<T> {
             ClientError::Connect(err) => { Err(anyhow!("{}", err.source().unwrap().to_string())) }
             ClientError::Disconnect(_err) => {
                 Err(anyhow!("remote server disconnect"))
-                // todo 增加断线重连机制
             }
             ClientError::Status(status) => { Err(anyhow!("{}: {}", status.code(), status.message())) }
+            ClientError::Incompatible(msg) => { Err(anyhow!("incompatible server: {}", msg)) }
             ClientError::Other(err) => { Err(err) }
         };
     }
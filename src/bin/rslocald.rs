@@ -1,20 +1,169 @@
-use clap::{Parser};
+use std::io::IsTerminal;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, UNIX_EPOCH};
+use clap::{Args, Parser, Subcommand};
 use env_logger::Env;
-use rslocal::server::{Config, Tunnel};
+use inquire::Password;
+use anyhow::{anyhow, bail, Context};
+use serde_json::{json, Value};
+use tonic::{Request, Status};
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::Interceptor;
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint};
+use rslocal::server::{Config, generate_token, hash_token, HashAlgorithm, parse_expiry, SCOPES, Tunnel};
+use rslocal::server::api::{CloseTunnelParam, CreateTokenParam, Protocol, RevokeSessionParam, RevokeTokenParam};
+use rslocal::server::api::admin_client::AdminClient;
+
+const ADMIN_TOKEN_ENV: &str = "RSLOCALD_ADMIN_TOKEN";
 
 /// A fictional versioning CLI
 #[derive(Debug, Parser)]
 #[clap(name = "rslocald")]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Commands>,
+
     /// config file of rslocal
     #[clap(short, long, default_value_t = String::from("rslocald"))]
     config: String,
 }
 
+#[derive(Debug, Subcommand)]
+enum Commands {
+    /// manage the tokens of the [tokens] table
+    Token {
+        #[clap(subcommand)]
+        command: TokenCommands,
+    },
+    /// manage a running server through its admin API
+    Admin(AdminArgs),
+}
+
+#[derive(Debug, Subcommand)]
+enum TokenCommands {
+    /// print the [tokens] entry of a new random token
+    Hash {
+        /// hash an existing token instead, entered when prompted or read from stdin
+        #[clap(long)]
+        existing: bool,
+        /// sha256 or argon2
+        #[clap(short, long, default_value_t = String::from("sha256"))]
+        algorithm: String,
+        /// UTC time the token expires at, e.g. 2024-07-01 or 2024-07-01T12:00:00Z
+        #[clap(short, long)]
+        expires: Option<String>,
+        /// scope granted to the token (tunnel:http, tunnel:tcp, admin), all but admin if omitted
+        #[clap(short, long = "scope")]
+        scopes: Vec<String>,
+        /// note on who the token was handed out to
+        #[clap(short, long)]
+        description: Option<String>,
+    },
+}
+
+#[derive(Debug, Args)]
+struct AdminArgs {
+    /// url of the admin API, defaults to the [admin] bind_addr of the config
+    #[clap(long)]
+    server: Option<String>,
+    /// token with the admin scope, discouraged as other local users can read it from the
+    /// process list; set $RSLOCALD_ADMIN_TOKEN or enter it when prompted instead
+    #[clap(long)]
+    token: Option<String>,
+    /// CA bundle to verify the server with instead of the system roots
+    #[clap(long)]
+    ca_cert: Option<String>,
+    /// name the server certificate is issued for, when it differs from the host of the url
+    #[clap(long)]
+    tls_domain: Option<String>,
+    /// print JSON instead of a table
+    #[clap(long)]
+    json: bool,
+
+    #[clap(subcommand)]
+    command: AdminCommands,
+}
+
+#[derive(Debug, Subcommand)]
+enum AdminCommands {
+    /// re-read the config file of the server like SIGHUP
+    Reload,
+    /// list and close tunnels
+    Tunnels {
+        #[clap(subcommand)]
+        command: TunnelCommands,
+    },
+    /// list and revoke login sessions
+    Sessions {
+        #[clap(subcommand)]
+        command: SessionCommands,
+    },
+    /// list, create and revoke tokens
+    Tokens {
+        #[clap(subcommand)]
+        command: AdminTokenCommands,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum TunnelCommands {
+    List,
+    /// close a tunnel, its client is told and does not reconnect
+    Kill {
+        /// entrypoint or id of the tunnel
+        tunnel: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum SessionCommands {
+    List,
+    /// end a session and its tunnels, the user may log in again
+    Revoke {
+        /// id of the session
+        #[clap(required_unless_present = "user")]
+        id: Option<String>,
+        /// revoke all sessions of this user instead
+        #[clap(short, long, conflicts_with = "id")]
+        user: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum AdminTokenCommands {
+    List,
+    /// create a token kept by the server, it is printed only once
+    Create {
+        username: String,
+        /// UTC time the token expires at, e.g. 2024-07-01 or 2024-07-01T12:00:00Z
+        #[clap(short, long)]
+        expires: Option<String>,
+        /// scope granted to the token (tunnel:http, tunnel:tcp, admin), all but admin if omitted
+        #[clap(short, long = "scope")]
+        scopes: Vec<String>,
+        /// note on who the token was handed out to
+        #[clap(short, long)]
+        description: Option<String>,
+    },
+    /// remove a token created with `admin tokens create` and revoke the sessions of its user
+    Revoke {
+        username: String,
+    },
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Cli::parse();
+    match args.command {
+        Some(Commands::Token { command: TokenCommands::Hash { existing, algorithm, expires, scopes, description } }) => {
+            return print_token_entry(existing, &algorithm, expires, scopes, description);
+        }
+        Some(Commands::Admin(admin)) => return run_admin(admin, &args.config).await,
+        None => {}
+    }
+
     let configfile = &args.config;
 
     let cfg = Config::new(configfile)?;
@@ -24,6 +173,369 @@ async fn main() -> anyhow::Result<()> {
     }
     env_logger::Builder::from_env(env).init();
 
-    let tunnel = Tunnel::new(cfg);
+    let tunnel = Tunnel::new(cfg).with_config_file(configfile);
     tunnel.start().await
 }
+
+fn print_token_entry(existing: bool, algorithm: &str, expires: Option<String>, scopes: Vec<String>, description: Option<String>) -> anyhow::Result<()> {
+    let algorithm: HashAlgorithm = algorithm.parse().map_err(anyhow::Error::msg)?;
+    let token = if existing { read_token()? } else { generate_token() };
+    let entry = token_entry(&token, algorithm, expires, scopes, description)?;
+    if !existing {
+        println!("token: {}", token);
+    }
+    println!("entry: {}", entry);
+    Ok(())
+}
+
+// 令牌不经过命令行参数，避免出现在进程列表和shell历史里
+fn read_token() -> anyhow::Result<String> {
+    if std::io::stdin().is_terminal() {
+        return Ok(Password::new("token?").prompt()?);
+    }
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    let token = line.trim_end_matches(['\r', '\n']);
+    if token.is_empty() {
+        bail!("expected the token on stdin");
+    }
+    Ok(token.to_string())
+}
+
+/// Formats the value of a `[tokens]` entry, an inline table when it has restrictions.
+fn token_entry(token: &str, algorithm: HashAlgorithm, expires: Option<String>, scopes: Vec<String>, description: Option<String>) -> anyhow::Result<String> {
+    if let Some(expires) = &expires {
+        parse_expiry(expires)?;
+    }
+    if let Some(scope) = scopes.iter().find(|s| !SCOPES.contains(&s.as_str())) {
+        bail!("unknown scope {}, expected one of {}", scope, SCOPES.join(", "));
+    }
+    let entry = hash_token(token, algorithm)?;
+    if expires.is_none() && scopes.is_empty() && description.is_none() {
+        return Ok(json!(entry).to_string());
+    }
+
+    // 有附加限制时输出内联表，JSON的字符串和数组也是合法的TOML
+    let mut fields = vec![format!("token = {}", json!(entry))];
+    if let Some(expires) = expires {
+        fields.push(format!("expires = {}", json!(expires)));
+    }
+    if !scopes.is_empty() {
+        fields.push(format!("scopes = {}", json!(scopes)));
+    }
+    if let Some(description) = description {
+        fields.push(format!("description = {}", json!(description)));
+    }
+    Ok(format!("{{ {} }}", fields.join(", ")))
+}
+
+/// Sends the admin token with every call.
+#[derive(Clone)]
+struct BearerToken(MetadataValue<Ascii>);
+
+impl Interceptor for BearerToken {
+    fn call(&mut self, mut req: Request<()>) -> Result<Request<()>, Status> {
+        req.metadata_mut().insert("authorization", self.0.clone());
+        Ok(req)
+    }
+}
+
+type Admin = AdminClient<InterceptedService<Channel, BearerToken>>;
+
+// 默认连接本机配置的管理端口
+async fn connect_admin(args: &AdminArgs, configfile: &str) -> anyhow::Result<Admin> {
+    let token = match &args.token {
+        Some(token) => token.clone(),
+        None => match std::env::var(ADMIN_TOKEN_ENV) {
+            Ok(token) => token,
+            // 交互使用时提示输入，令牌不会出现在命令行里
+            Err(_) if std::io::stdin().is_terminal() => Password::new("admin token?").prompt()?,
+            Err(_) => bail!("an admin token is required, set {}", ADMIN_TOKEN_ENV),
+        },
+    };
+    let server = match &args.server {
+        Some(server) => server.clone(),
+        None => {
+            let cfg = Config::new(configfile).map_err(|e| anyhow!("pass --server or a config with [admin]: {}", e))?;
+            let admin = cfg.admin.as_ref().ok_or_else(|| anyhow!("[admin] is not configured, pass --server"))?;
+            let scheme = if cfg.tls.is_some() { "https" } else { "http" };
+            format!("{}://{}", scheme, local_addr(&admin.bind_addr))
+        }
+    };
+
+    let mut endpoint = Endpoint::from_shared(server.clone())?;
+    if server.starts_with("https://") || args.ca_cert.is_some() || args.tls_domain.is_some() {
+        let mut tls = ClientTlsConfig::new();
+        if let Some(path) = &args.ca_cert {
+            let ca = std::fs::read(path).map_err(|e| anyhow!("failed to read {}: {}", path, e))?;
+            tls = tls.ca_certificate(Certificate::from_pem(ca));
+        }
+        // 默认连接回环地址，证书一般不包含它
+        if let Some(domain) = &args.tls_domain {
+            tls = tls.domain_name(domain);
+        }
+        endpoint = endpoint.tls_config(tls)?;
+    }
+    let channel = endpoint.connect().await.with_context(|| format!("failed to connect to {}", server))?;
+    let bearer = format!("Bearer {}", token).parse().map_err(|_| anyhow!("invalid admin token"))?;
+    Ok(AdminClient::with_interceptor(channel, BearerToken(bearer)))
+}
+
+// 监听所有地址时连接本机的回环地址
+fn local_addr(bind_addr: &str) -> String {
+    match bind_addr.parse::<SocketAddr>() {
+        Ok(addr) if addr.ip().is_unspecified() => {
+            let ip = if addr.is_ipv4() { Ipv4Addr::LOCALHOST.into() } else { Ipv6Addr::LOCALHOST.into() };
+            SocketAddr::new(ip, addr.port()).to_string()
+        }
+        _ => bind_addr.to_string(),
+    }
+}
+
+async fn run_admin(args: AdminArgs, configfile: &str) -> anyhow::Result<()> {
+    let mut admin = connect_admin(&args, configfile).await?;
+    let status = |s: Status| anyhow!("{:?}: {}", s.code(), s.message());
+    let output = match args.command {
+        AdminCommands::Reload => {
+            let reply = admin.reload(()).await.map_err(status)?.into_inner();
+            let mut lines = reply.changes.clone();
+            if lines.is_empty() {
+                lines.push("no changes".to_string());
+            }
+            lines.extend(reply.restart_required.iter().map(|name| format!("restart to apply {}", name)));
+            if reply.revoked_sessions > 0 {
+                lines.push(format!("revoked {} sessions", reply.revoked_sessions));
+            }
+            Output::Lines(lines, json!({
+                "changes": reply.changes,
+                "restart_required": reply.restart_required,
+                "revoked_sessions": reply.revoked_sessions,
+            }))
+        }
+        AdminCommands::Tunnels { command: TunnelCommands::List } => {
+            let tunnels = admin.list_tunnels(()).await.map_err(status)?.into_inner().tunnels;
+            let rows = tunnels.iter().map(|t| vec![
+                t.entrypoint.clone(),
+                t.username.clone(),
+                t.remote_addr.clone(),
+                t.connections.to_string(),
+                t.connections_total.to_string(),
+                format_bytes(t.bytes_in),
+                format_bytes(t.bytes_out),
+                format_age(t.created_at),
+            ]).collect();
+            let json = tunnels.iter().map(|t| json!({
+                "id": t.id,
+                "entrypoint": t.entrypoint,
+                "protocol": protocol_name(t.protocol),
+                "username": t.username,
+                "session_id": t.session_id,
+                "remote_addr": t.remote_addr,
+                "created_at": t.created_at,
+                "connections": t.connections,
+                "connections_total": t.connections_total,
+                "bytes_in": t.bytes_in,
+                "bytes_out": t.bytes_out,
+            })).collect();
+            Output::Table(vec!["ENTRYPOINT", "USER", "CLIENT", "CONNS", "TOTAL", "IN", "OUT", "AGE"], rows, json)
+        }
+        AdminCommands::Tunnels { command: TunnelCommands::Kill { tunnel } } => {
+            admin.close_tunnel(CloseTunnelParam { id: tunnel.clone() }).await.map_err(status)?;
+            Output::Lines(vec![format!("closed tunnel {}", tunnel)], json!({ "closed": tunnel }))
+        }
+        AdminCommands::Sessions { command: SessionCommands::List } => {
+            let sessions = admin.list_sessions(()).await.map_err(status)?.into_inner().sessions;
+            let rows = sessions.iter().map(|s| vec![
+                s.id.clone(),
+                s.username.clone(),
+                s.groups.join(","),
+                s.tunnels.to_string(),
+                format_age(s.created_at),
+                format_time(s.expires_at),
+            ]).collect();
+            let json = sessions.iter().map(|s| json!({
+                "id": s.id,
+                "username": s.username,
+                "groups": s.groups,
+                "created_at": s.created_at,
+                "expires_at": s.expires_at,
+                "tunnels": s.tunnels,
+            })).collect();
+            Output::Table(vec!["ID", "USER", "GROUPS", "TUNNELS", "AGE", "EXPIRES"], rows, json)
+        }
+        AdminCommands::Sessions { command: SessionCommands::Revoke { id, user } } => {
+            let param = RevokeSessionParam { id: id.unwrap_or_default(), username: user.unwrap_or_default() };
+            let revoked = admin.revoke_session(param).await.map_err(status)?.into_inner().revoked_sessions;
+            Output::Lines(vec![format!("revoked {} sessions", revoked)], json!({ "revoked_sessions": revoked }))
+        }
+        AdminCommands::Tokens { command: AdminTokenCommands::List } => {
+            let tokens = admin.list_tokens(()).await.map_err(status)?.into_inner().tokens;
+            let rows = tokens.iter().map(|t| vec![
+                t.username.clone(),
+                if t.scopes.is_empty() { "*".to_string() } else { t.scopes.join(",") },
+                format_time(t.expires_at),
+                if t.managed { "admin" } else { "config" }.to_string(),
+                t.description.clone(),
+            ]).collect();
+            let json = tokens.iter().map(|t| json!({
+                "username": t.username,
+                "description": t.description,
+                "expires_at": t.expires_at,
+                "scopes": t.scopes,
+                "managed": t.managed,
+            })).collect();
+            Output::Table(vec!["USER", "SCOPES", "EXPIRES", "SOURCE", "DESCRIPTION"], rows, json)
+        }
+        AdminCommands::Tokens { command: AdminTokenCommands::Create { username, expires, scopes, description } } => {
+            let param = CreateTokenParam { username: username.clone(), expires: expires.unwrap_or_default(), scopes, description: description.unwrap_or_default() };
+            let reply = admin.create_token(param).await.map_err(status)?.into_inner();
+            Output::Lines(vec![format!("token: {}", reply.token)], json!({
+                "username": username,
+                "token": reply.token,
+                "expires_at": reply.expires_at,
+            }))
+        }
+        AdminCommands::Tokens { command: AdminTokenCommands::Revoke { username } } => {
+            let revoked = admin.revoke_token(RevokeTokenParam { username: username.clone() }).await.map_err(status)?.into_inner().revoked_sessions;
+            Output::Lines(vec![format!("revoked the token of {} and {} sessions", username, revoked)], json!({ "revoked_sessions": revoked }))
+        }
+    };
+    output.print(args.json);
+    Ok(())
+}
+
+/// The result of an admin command, printed for humans or as JSON.
+enum Output {
+    Table(Vec<&'static str>, Vec<Vec<String>>, Vec<Value>),
+    Lines(Vec<String>, Value),
+}
+
+impl Output {
+    fn print(self, as_json: bool) {
+        match (self, as_json) {
+            (Output::Table(_, _, json), true) => println!("{}", Value::Array(json)),
+            (Output::Lines(_, json), true) => println!("{}", json),
+            (Output::Table(headers, rows, _), false) => print_table(&headers, &rows),
+            (Output::Lines(lines, _), false) => lines.iter().for_each(|line| println!("{}", line)),
+        }
+    }
+}
+
+// 按每列最长的值对齐
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells.iter().zip(&widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(headers.to_vec());
+    for row in rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
+
+fn protocol_name(protocol: i32) -> &'static str {
+    match Protocol::from_i32(protocol) {
+        Some(Protocol::Http) => "http",
+        Some(Protocol::Tcp) => "tcp",
+        None => "unknown",
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
+
+fn format_age(since: u64) -> String {
+    let now = UNIX_EPOCH.elapsed().map(|d| d.as_secs()).unwrap_or_default();
+    humantime::format_duration(Duration::from_secs(now.saturating_sub(since))).to_string()
+}
+
+fn format_time(at: u64) -> String {
+    match at {
+        0 => "never".to_string(),
+        at => humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(at)).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use config::{File, FileFormat};
+    use rslocal::server::{Authenticator, Credentials, StaticTokens, TokenEntry};
+    use super::*;
+
+    const TOKEN: &str = "rslocald_Pz3xLq8VwN5tKe2M";
+
+    fn parse_entry(entry: &str) -> TokenEntry {
+        let tokens: HashMap<String, TokenEntry> = config::Config::builder()
+            .add_source(File::from_str(&format!("alice = {}", entry), FileFormat::Toml))
+            .build().unwrap()
+            .try_deserialize().unwrap();
+        tokens["alice"].clone()
+    }
+
+    async fn login(entry: TokenEntry, token: &str) -> Option<rslocal::server::Identity> {
+        let tokens = StaticTokens::new(HashMap::from([("alice".to_string(), entry)])).unwrap();
+        tokens.authenticate(&Credentials { token: token.to_string(), peer_certs: None }).await.ok()
+    }
+
+    #[test]
+    fn plain_entries_are_strings() {
+        let entry = token_entry(TOKEN, HashAlgorithm::Sha256, None, vec![], None).unwrap();
+        assert_eq!(entry, "\"sha256:5cf0bf7a60940c8bd57225f8f5cd9eb8eeb097c5e9bc564c2f147d48b0f02c80\"");
+        assert!(matches!(parse_entry(&entry), TokenEntry::Token(t) if t.starts_with("sha256:")));
+    }
+
+    #[test]
+    fn restricted_entries_are_inline_tables() {
+        let scopes = vec!["tunnel:http".to_string(), "admin".to_string()];
+        let description = Some("ACME \"demo\"\t\u{1}".to_string());
+        let entry = token_entry(TOKEN, HashAlgorithm::Sha256, Some("2999-01-01".to_string()), scopes.clone(), description.clone()).unwrap();
+        match parse_entry(&entry) {
+            TokenEntry::Detailed(cfg) => {
+                assert!(cfg.token.starts_with("sha256:"));
+                assert_eq!(cfg.expires.as_deref(), Some("2999-01-01"));
+                assert_eq!(cfg.scopes, Some(scopes));
+                assert_eq!(cfg.description, description);
+            }
+            entry => panic!("expected a table: {:?}", entry),
+        }
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        assert!(token_entry(TOKEN, HashAlgorithm::Sha256, Some("soon".to_string()), vec![], None).is_err());
+        assert!(token_entry(TOKEN, HashAlgorithm::Sha256, None, vec!["tunnel:udp".to_string()], None).is_err());
+        assert!(token_entry("weak", HashAlgorithm::Sha256, None, vec![], None).is_err());
+    }
+
+    #[tokio::test]
+    async fn entries_authenticate_their_token() {
+        for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Argon2] {
+            let entry = parse_entry(&token_entry(TOKEN, algorithm, None, vec![], None).unwrap());
+            assert_eq!(login(entry.clone(), TOKEN).await.unwrap().username, "alice");
+            assert!(login(entry, "rslocald_Wq8ZkP3nVx7Lm2Rt").await.is_none());
+        }
+
+        let entry = token_entry(TOKEN, HashAlgorithm::Argon2, Some("2999-01-01".to_string()), vec!["tunnel:tcp".to_string()], None).unwrap();
+        let identity = login(parse_entry(&entry), TOKEN).await.unwrap();
+        assert_eq!(identity.scopes, Some(vec!["tunnel:tcp".to_string()]));
+        assert_eq!(identity.expires_at, Some(parse_expiry("2999-01-01").unwrap()));
+    }
+}
@@ -4,26 +4,35 @@ pub mod api {
 
 use std::error::Error;
 use std::str::FromStr;
-use futures::FutureExt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{Instant, sleep_until};
 use anyhow::anyhow;
-use log::{debug, info};
+use dashmap::DashMap;
+use log::{debug, info, warn};
 use tokio::{io};
 use tokio::net::{TcpStream};
 use tokio::sync::{mpsc};
+use tokio::sync::mpsc::{Sender, UnboundedReceiver, UnboundedSender};
 
-use tonic::transport::{Channel, Endpoint};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::{Request, Status};
+use tonic::{Request, Status, Streaming};
 use crate::server::api::tunnel_client::TunnelClient;
 use crate::server::api::user_client::UserClient;
-use crate::server::api::{LoginBody, LoginReply, TransferBody, TransferReply, ListenParam, Protocol, TStatus};
+use crate::server::api::{LoginBody, LoginReply, TransferBody, TransferReply, ListenParam, Protocol, TStatus, Visitor};
+use crate::server::api::listen_notification::Event;
 use thiserror::Error;
 use tokio::io::{AsyncWriteExt};
 use tokio_stream::StreamExt;
-use tokio_util::sync::{PollSender};
+use tokio_util::sync::{CancellationToken, PollSender};
 use tonic::codegen::{InterceptedService};
 use tonic::service::Interceptor;
-use crate::{RxReader, TxWriter};
+use crate::{protocol, RxReader, TxWriter};
+use crate::mux::{self, Window};
+
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(20);
 
 #[derive(Error, Debug)]
 pub enum ClientError {
@@ -36,6 +45,9 @@ pub enum ClientError {
     #[error("{0}")]
     Status(#[from] tonic::Status),
 
+    #[error("{0}")]
+    Incompatible(String),
+
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -59,96 +71,293 @@ impl Interceptor for SessionInterceptor {
 }
 
 
+/// TLS options for the connection to the server, all files are PEM encoded.
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// CA bundle used instead of the system roots to verify the server.
+    pub ca_cert: Option<String>,
+    /// Client certificate and key, for servers requiring mTLS.
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+impl TlsOptions {
+    fn is_empty(&self) -> bool {
+        self.ca_cert.is_none() && self.client_cert.is_none() && self.client_key.is_none()
+    }
+
+    fn client_config(&self) -> anyhow::Result<ClientTlsConfig> {
+        let read = |path: &String| std::fs::read(path).map_err(|e| anyhow!("failed to read {}: {}", path, e));
+        let mut config = ClientTlsConfig::new();
+        if let Some(path) = &self.ca_cert {
+            config = config.ca_certificate(Certificate::from_pem(read(path)?));
+        }
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => config = config.identity(Identity::from_pem(read(cert)?, read(key)?)),
+            (None, None) => {}
+            _ => return Err(anyhow!("client_cert and client_key must be set together")),
+        }
+        Ok(config)
+    }
+}
+
 pub struct Tunnel {
     client: TunnelClient<InterceptedService<Channel, SessionInterceptor>>,
+    user: UserClient<InterceptedService<Channel, SessionInterceptor>>,
 
     user_info: LoginReply,
 }
 
 impl Tunnel {
     // 连接服务器并完成登录
-    pub async fn connect(endpoint: &str, token: &str) -> Result<Tunnel, ClientError> {
-        let ep = Endpoint::from_str(endpoint)?;
+    pub async fn connect(endpoint: &str, token: &str, tls: &TlsOptions) -> Result<Tunnel, ClientError> {
+        let mut ep = Endpoint::from_str(endpoint)?
+            .http2_keep_alive_interval(KEEPALIVE_INTERVAL)
+            .keep_alive_timeout(KEEPALIVE_TIMEOUT)
+            .keep_alive_while_idle(true);
+        if endpoint.starts_with("https://") || !tls.is_empty() {
+            ep = ep.tls_config(tls.client_config()?)?;
+        }
         let channel = ep.connect().await?;
 
         // 登录逻辑，使用Token连接服务器获取session_id
-        let req = Request::new(LoginBody { token: token.to_string() });
+        let req = Request::new(LoginBody {
+            token: token.to_string(),
+            protocol_version: protocol::PROTOCOL_VERSION,
+            capabilities: protocol::capabilities(),
+        });
         let login_svc = UserClient::new(channel.clone()).login(req).await?;
         let user_info = login_svc.into_inner();
+        protocol::check_version("server", user_info.protocol_version).map_err(ClientError::Incompatible)?;
 
         // 注入session_id
         let interceptor = SessionInterceptor::new(user_info.session_id.clone());
+        let user = UserClient::with_interceptor(channel.clone(), interceptor.clone());
         let client = TunnelClient::with_interceptor(channel, interceptor);
-        Ok(Tunnel { client, user_info })
+        Ok(Tunnel { client, user, user_info })
     }
 
     async fn build_tunnel(&mut self, protocol: Protocol, target: String, subdomain: String) -> Result<(), ClientError> {
         debug!("protocol: {:?}, target: {:?}", protocol, target);
-        let response = self.client.listen(ListenParam { protocol: protocol.into(), subdomain }).await?;
+        let response = self.client.listen(ListenParam {
+            protocol: protocol.into(),
+            subdomain,
+            protocol_version: protocol::PROTOCOL_VERSION,
+            capabilities: self.user_info.capabilities.clone(),
+        }).await?;
         let mut resp_stream = response.into_inner();
-        while let Some(resp_stream_result) = resp_stream.next().await {
+        // 控制流结束时一并关闭Transfer流
+        let transfer_closed = CancellationToken::new();
+        let _transfer_guard = transfer_closed.clone().drop_guard();
+        let mut transfer_tx = None;
+        // 开启心跳后，超过约定时间没有收到服务端的消息即视为断线
+        let mut heartbeat_timeout = None;
+        let mut deadline = Instant::now();
+        loop {
+            let resp_stream_result = tokio::select! {
+                result = resp_stream.next() => match result {
+                    Some(result) => result,
+                    None => return Err(ClientError::Disconnect(anyhow!("control stream closed"))),
+                },
+                _ = transfer_closed.cancelled() => return Err(ClientError::Disconnect(anyhow!("transfer stream closed"))),
+                _ = sleep_until(deadline), if heartbeat_timeout.is_some() => {
+                    return Err(ClientError::Disconnect(anyhow!("server stopped sending heartbeats")));
+                }
+            };
             if let Err(err) = resp_stream_result {
                 return Err(ClientError::Disconnect(anyhow!(err)));
             }
 
             let ln = resp_stream_result.unwrap(); //todo 处理连接断开的情况
-            match ln.action.as_str() {
-                "ready" => {
+            if let Some(timeout) = heartbeat_timeout {
+                deadline = Instant::now() + timeout;
+            }
+            match ln.event {
+                Some(Event::Ready(ready)) => {
+                    debug!("protocol version: {}, capabilities: {:?}", ready.protocol_version, ready.capabilities);
+                    transfer_tx = Some(self.attach(ready.tunnel_id, target.clone(), transfer_closed.clone()).await?);
+                    if ready.heartbeat_interval > 0 {
+                        let timeout = Duration::from_secs(ready.heartbeat_interval.saturating_add(ready.heartbeat_timeout) as u64);
+                        heartbeat_timeout = Some(timeout);
+                        deadline = Instant::now() + timeout;
+                    }
                     println!("Username: {}", self.user_info.username);
-                    println!("Forwarding: {} => {}", ln.message, target);
+                    println!("Forwarding: {} => {}", ready.entrypoint, target);
                 }
-                "coming" => {
-                    debug!("conn_id: {:?}", ln.message);
-                    let client = self.client.clone();
-                    let target = target.clone();
-                    tokio::spawn(async move {
-                        coming_handle(client, ln.message, protocol, target).await;
-                    });
+                Some(Event::Warning(warning)) => {
+                    warn!("{}", warning.message);
                 }
-                _ => {}
+                Some(Event::Error(err)) => {
+                    return Err(ClientError::Other(anyhow!(err.message)));
+                }
+                Some(Event::Shutdown(shutdown)) => {
+                    return Err(ClientError::Disconnect(anyhow!(shutdown.reason)));
+                }
+                Some(Event::Ping(_)) => {
+                    if let Some(tx) = &transfer_tx {
+                        let _ = tx.send(TransferBody { status: TStatus::Pong as i32, ..Default::default() }).await;
+                    }
+                }
+                None => {}
             }
         }
-        Ok(())
+    }
+
+    // 建立隧道唯一的Transfer流，所有连接都复用它
+    async fn attach(&mut self, tunnel_id: String, target: String, closed: CancellationToken) -> Result<Sender<TransferBody>, ClientError> {
+        let (tx, rx) = mpsc::channel(128);
+        let mut req = Request::new(ReceiverStream::new(rx));
+        let tunnel_id = tunnel_id.parse().map_err(|e| ClientError::Other(anyhow!("invalid tunnel id: {}", e)))?;
+        req.metadata_mut().insert(mux::TUNNEL_ID_KEY, tunnel_id);
+        let response = self.client.transfer(req).await?;
+        let out = tx.clone();
+        tokio::spawn(async move {
+            dispatch_frames(response.into_inner(), tx, target, closed.clone()).await;
+            closed.cancel();
+        });
+        Ok(out)
     }
 
     pub async fn start(&mut self, protocol: Protocol, target: String, subdomain: &str) -> Result<(), ClientError> {
         self.build_tunnel(protocol, target, subdomain.to_string()).await
     }
+
+    /// Ends the session on the server, closing the tunnels opened with it.
+    pub async fn logout(&mut self) -> Result<(), ClientError> {
+        self.user.logout(()).await?;
+        Ok(())
+    }
 }
 
-type TunClient = TunnelClient<InterceptedService<Channel, SessionInterceptor>>;
+/// Client side state of one connection multiplexed over the `Transfer` stream.
+struct MuxStream {
+    inbound: UnboundedSender<TransferReply>,
+    window: Window,
+}
 
-// 这个函数里处理的是一次完整请求
-async fn coming_handle(mut client: TunClient, conn_id: String, _protocol: Protocol, target: String) {
-    let (tx, rx) = mpsc::channel(128);
-    tx.send(TransferBody { conn_id: conn_id.clone(), status: TStatus::Ready as i32, resp_data: vec![] }).await.unwrap(); // 通过conn_id连入服务端
+// 把服务端发来的帧分发给对应的连接
+async fn dispatch_frames(mut in_stream: Streaming<TransferReply>, out: Sender<TransferBody>, target: String, closed: CancellationToken) {
+    let streams: Arc<DashMap<String, MuxStream>> = Default::default();
+    loop {
+        let tr = tokio::select! {
+            result = in_stream.next() => match result {
+                Some(Ok(tr)) => tr,
+                _ => break,
+            },
+            _ = closed.cancelled() => break,
+        };
+        match TStatus::from_i32(tr.status) {
+            Some(TStatus::Ready) => {
+                debug!("conn_id: {:?}", tr.conn_id);
+                if let Some(visitor) = &tr.visitor {
+                    visitor_log(visitor);
+                }
+                let (in_tx, in_rx) = mpsc::unbounded_channel();
+                let window = Window::default();
+                streams.insert(tr.conn_id.clone(), MuxStream { inbound: in_tx, window: window.clone() });
+                tokio::spawn(coming_handle(tr.conn_id, in_rx, window, out.clone(), streams.clone(), target.clone()));
+            }
+            Some(TStatus::Window) => {
+                if let Some(stream) = streams.get(tr.conn_id.as_str()) {
+                    stream.window.release(tr.window as usize);
+                }
+            }
+            status => {
+                if let Some(stream) = streams.get(tr.conn_id.as_str()) {
+                    // 连接被中止后不会再收到额度，唤醒等待发送的任务
+                    if matches!(status, Some(TStatus::Reset | TStatus::Error)) {
+                        stream.window.close();
+                    }
+                    let _ = stream.inbound.send(tr);
+                }
+            }
+        }
+    }
+
+    debug!("transfer stream closed");
+    for stream in streams.iter() {
+        stream.window.close();
+    }
+    streams.clear();
+}
 
+// 这个函数里处理的是一次完整请求
+async fn coming_handle(conn_id: String, mut in_rx: UnboundedReceiver<TransferReply>, window: Window, out: Sender<TransferBody>,
+                       streams: Arc<DashMap<String, MuxStream>>, target: String) {
+    let (tx, mut rx) = mpsc::channel::<TransferBody>(128);
     let (tx2, rx2) = mpsc::channel(128);
-    let inbound_reader = RxReader { rx: rx2 };
-    let inbound_writer = TxWriter { conn_id, tx: PollSender::new(tx) };
+    let inbound_reader = RxReader::new(rx2);
+    let inbound_writer = TxWriter { conn_id: conn_id.clone(), tx: PollSender::new(tx.clone()) };
+
+    // 请求数据交给本地服务后归还发送额度
+    let (outc, id) = (out.clone(), conn_id.clone());
     tokio::spawn(async move {
-        let response = client.transfer(ReceiverStream::new(rx)).await.unwrap();
-        let mut resp_stream = response.into_inner();
-        while let Some(received) = resp_stream.next().await {
-            let tr = received.unwrap();
-            tx2.send(tr).await.unwrap();
+        while let Some(tr) = in_rx.recv().await {
+            let n = tr.req_data.len() as u32;
+            let done = tr.status != TStatus::Working as i32;
+            if tx2.send(tr).await.is_err() {
+                break;
+            }
+            if n > 0 {
+                let _ = outc.send(TransferBody { conn_id: id.clone(), status: TStatus::Window as i32, window: n, ..Default::default() }).await;
+            }
+            if done {
+                break;
+            }
         }
     });
 
-    tokio::spawn(transfer_to_target(inbound_reader, inbound_writer, target).map(|r| {
-        debug!("transfer map: {:?}", r);
-        if let Err(e) = r {
-            println!("Failed to transfer; error={}", e);
+    // 本地服务的响应在发送额度内转发给服务端，已排队的小块数据合并发送
+    let id = conn_id.clone();
+    let sender = tokio::spawn(async move {
+        let mut next = rx.recv().await;
+        while let Some(tb) = next.take() {
+            if tb.status != TStatus::Working as i32 {
+                if out.send(tb).await.is_err() {
+                    return;
+                }
+                next = rx.recv().await;
+                continue;
+            }
+
+            let (data, rest) = mux::coalesce(tb.resp_data, &mut rx, |tb| {
+                if tb.status == TStatus::Working as i32 { Ok(tb.resp_data) } else { Err(tb) }
+            });
+            for chunk in mux::split(data) {
+                let frame = TransferBody { conn_id: id.clone(), status: TStatus::Working as i32, resp_data: chunk, ..Default::default() };
+                if !window.acquire(frame.resp_data.len()).await || out.send(frame).await.is_err() {
+                    return;
+                }
+            }
+            next = match rest {
+                Some(tb) => Some(tb),
+                None => rx.recv().await,
+            };
         }
-    }));
+    });
+
+    if let Err(e) = transfer_to_target(inbound_reader, inbound_writer, target, tx).await {
+        println!("Failed to transfer; error={}", e);
+    }
+    let _ = sender.await;
+    streams.remove(&conn_id);
     // if protocol == Protocol::Http {
     //     http_access_log(req_data, resp);
     // }
 }
 
-async fn transfer_to_target(mut ri: RxReader<TransferReply>, mut wi: TxWriter<TransferBody>, proxy_addr: String) -> Result<(), Box<dyn Error>> {
+async fn transfer_to_target(mut ri: RxReader<TransferReply>, mut wi: TxWriter<TransferBody>, proxy_addr: String, ctl: Sender<TransferBody>) -> Result<(), Box<dyn Error>> {
     debug!("transfer");
-    let mut outbound = TcpStream::connect(proxy_addr).await?;
+    let conn_id = wi.conn_id.clone();
+    let mut outbound = match TcpStream::connect(&proxy_addr).await {
+        Ok(outbound) => outbound,
+        Err(e) => {
+            // 告知服务端本地服务不可用
+            let reason = format!("failed to connect to {}: {}", proxy_addr, e);
+            let _ = ctl.send(TransferBody { conn_id, status: TStatus::Error as i32, reason: reason.clone(), ..Default::default() }).await;
+            return Err(reason.into());
+        }
+    };
     let (mut ro, mut wo) = outbound.split();
 
     let client_to_server = async {
@@ -163,11 +372,24 @@ async fn transfer_to_target(mut ri: RxReader<TransferReply>, mut wi: TxWriter<Tr
         wi.shutdown().await
     };
 
-    tokio::try_join!(client_to_server, server_to_client)?;
+    if let Err(e) = tokio::try_join!(client_to_server, server_to_client) {
+        // 任意一端异常断开时，两端都以RST中止连接
+        let _ = ctl.send(TransferBody { conn_id, status: TStatus::Reset as i32, ..Default::default() }).await;
+        let _ = outbound.set_linger(Some(Duration::ZERO));
+        return Err(e.into());
+    }
 
     Ok(())
 }
 
+fn visitor_log(visitor: &Visitor) {
+    match &visitor.http {
+        Some(req) => info!("{} \"{} {}{}\"", visitor.remote_addr, req.method, req.host, req.path),
+        None => info!("{} connected to {}", visitor.remote_addr, visitor.entrypoint),
+    }
+}
+
+#[allow(dead_code)]
 fn http_access_log(_req_bytes: Vec<u8>, resp: Vec<u8>) {
     let resp_length = resp.len();
     let mut header_length = 1024;
@@ -1,9 +1,11 @@
 use std::fs;
-use std::path::PathBuf;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
 use config::Config;
 use inquire::Text;
 use inquire::validator::StringValidator;
 
+#[allow(dead_code)]
 const DEFAULT_CLOUD_ENDPOINT: &str = "https://localtest.rs/entrypoint";
 
 fn default() -> anyhow::Result<PathBuf> {
@@ -29,7 +31,94 @@ pub fn setup() -> anyhow::Result<()> {
     let cfg_content = format!("endpoint={}\ntoken={}", ep, token);
 
     let cfg_path = default()?;
-    fs::write(&cfg_path, cfg_content)?;
+    write_private(&cfg_path, &cfg_content)?;
     println!("config saved at {:?}", cfg_path);
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Sets (`Some`) or removes (`None`) keys in the default config file, keeping the other lines.
+pub fn save(values: &[(&str, Option<&str>)]) -> anyhow::Result<PathBuf> {
+    let cfg_path = default()?;
+    save_to(&cfg_path, values)?;
+    Ok(cfg_path)
+}
+
+fn save_to(cfg_path: &Path, values: &[(&str, Option<&str>)]) -> anyhow::Result<()> {
+    let content = fs::read_to_string(cfg_path).unwrap_or_default();
+    let mut lines: Vec<String> = content.lines()
+        .filter(|line| {
+            let key = line.split('=').next().unwrap_or_default().trim();
+            !values.iter().any(|(k, _)| *k == key)
+        })
+        .map(String::from)
+        .collect();
+    lines.extend(values.iter().filter_map(|(k, v)| v.map(|v| format!("{}={}", k, v))));
+
+    write_private(cfg_path, &(lines.join("\n") + "\n"))
+}
+
+// 配置中保存了令牌和refresh token，只允许当前用户读取。先写临时文件再改名，
+// 文件在任何时刻都不会被其他用户读到，中途失败也不会留下半个文件
+fn write_private(path: &Path, content: &str) -> anyhow::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    // 权限只在创建时设置，上次失败留下的临时文件要先删掉
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use crate::random_string;
+    use super::*;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("rslocal-config-{}.ini", random_string(8)))
+    }
+
+    #[test]
+    fn save_replaces_only_the_given_keys() {
+        let path = temp_path();
+        fs::write(&path, "endpoint=http://localhost:8422\ntoken=abc\nrefresh_token=old\n").unwrap();
+        save_to(&path, &[("refresh_token", Some("new")), ("token", None), ("issuer", Some("https://idp"))]).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "endpoint=http://localhost:8422\nrefresh_token=new\nissuer=https://idp\n");
+        fs::remove_file(&path).unwrap();
+
+        // 文件不存在时新建
+        save_to(&path, &[("token", Some("abc"))]).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "token=abc\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saved_config_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = temp_path();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        fs::write(&path, "endpoint=http://localhost:8422\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+        fs::write(&tmp, "").unwrap();
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o644)).unwrap();
+        save_to(&path, &[("refresh_token", Some("secret"))]).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert!(!tmp.exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
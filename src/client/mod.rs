@@ -1,4 +1,6 @@
 pub mod config;
+pub mod oidc;
+#[allow(clippy::module_inception)]
 mod client;
 
 pub use self::client::*;
\ No newline at end of file
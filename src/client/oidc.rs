@@ -0,0 +1,410 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use ::config::Config;
+use jsonwebtoken::{decode, DecodingKey, get_current_timestamp, Validation};
+use log::{debug, info, warn};
+use serde_derive::Deserialize;
+use tokio::time::sleep;
+use crate::client::config;
+
+pub const DEFAULT_SCOPE: &str = "openid profile email offline_access";
+
+const KEY_ISSUER: &str = "oidc_issuer";
+const KEY_CLIENT_ID: &str = "oidc_client_id";
+const KEY_TOKEN: &str = "oidc_token";
+const KEY_EXPIRES_AT: &str = "oidc_expires_at";
+const KEY_REFRESH_TOKEN: &str = "oidc_refresh_token";
+
+// 令牌临近过期时提前刷新，避免连接建立时恰好失效
+const REFRESH_BEFORE_EXPIRY: u64 = 60;
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    token_endpoint: String,
+    device_authorization_endpoint: Option<String>,
+    revocation_endpoint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    // 部分IdP（如Google）使用verification_url
+    #[serde(alias = "verification_url")]
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    #[serde(default = "default_interval")]
+    interval: u64,
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    id_token: Option<String>,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenError {
+    error: String,
+    error_description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Expiry {
+    exp: u64,
+}
+
+/// An OIDC login cached in the client config, refreshed on demand.
+#[derive(Debug)]
+pub struct OidcSession {
+    issuer: String,
+    client_id: String,
+    token: String,
+    expires_at: u64,
+    refresh_token: String,
+    http: reqwest::Client,
+}
+
+impl OidcSession {
+    /// Restores the session saved by `rslocal login`, if any.
+    pub fn from_config(cfg: &Config) -> Option<Self> {
+        Some(OidcSession {
+            issuer: cfg.get_string(KEY_ISSUER).ok()?,
+            client_id: cfg.get_string(KEY_CLIENT_ID).ok()?,
+            token: cfg.get_string(KEY_TOKEN).unwrap_or_default(),
+            expires_at: cfg.get_int(KEY_EXPIRES_AT).unwrap_or_default() as u64,
+            refresh_token: cfg.get_string(KEY_REFRESH_TOKEN).ok()?,
+            http: reqwest::Client::new(),
+        })
+    }
+
+    /// Returns a token valid for at least another minute, refreshing it if needed.
+    pub async fn token(&mut self) -> anyhow::Result<String> {
+        if !self.token.is_empty() && get_current_timestamp() + REFRESH_BEFORE_EXPIRY < self.expires_at {
+            return Ok(self.token.clone());
+        }
+
+        self.refresh().await?;
+        save(&self.issuer, &self.client_id, &self.token, self.expires_at, &self.refresh_token)?;
+        Ok(self.token.clone())
+    }
+
+    async fn refresh(&mut self) -> anyhow::Result<()> {
+        debug!("refreshing oidc token");
+        let discovery = discover(&self.http, &self.issuer).await?;
+        let params = [
+            ("grant_type", "refresh_token"),
+            ("refresh_token", self.refresh_token.as_str()),
+            ("client_id", self.client_id.as_str()),
+        ];
+        let resp = self.http.post(&discovery.token_endpoint).form(&params).send().await?;
+        let tokens = match token_response(resp).await? {
+            Ok(tokens) => tokens,
+            Err(err) => return Err(anyhow!("{}, please run `rslocal login` again", describe(&err))),
+        };
+
+        // 未返回新的refresh token时继续使用原来的
+        let refresh_token = tokens.refresh_token.clone().unwrap_or_else(|| self.refresh_token.clone());
+        let (token, expires_at) = pick_token(tokens);
+        self.token = token;
+        self.expires_at = expires_at;
+        self.refresh_token = refresh_token;
+        Ok(())
+    }
+}
+
+/// Runs the OAuth device authorization flow and caches the resulting tokens.
+pub async fn login(issuer: &str, client_id: &str, scope: &str) -> anyhow::Result<()> {
+    let tokens = device_flow(&reqwest::Client::new(), issuer, client_id, scope).await?;
+    let refresh_token = tokens.refresh_token.clone()
+        .ok_or_else(|| anyhow!("{} did not issue a refresh token, is offline_access allowed?", issuer))?;
+    let (token, expires_at) = pick_token(tokens);
+    let path = save(issuer, client_id, &token, expires_at, &refresh_token)?;
+    println!("login succeeded, credentials saved at {:?}", path);
+    Ok(())
+}
+
+async fn device_flow(http: &reqwest::Client, issuer: &str, client_id: &str, scope: &str) -> anyhow::Result<TokenResponse> {
+    let discovery = discover(http, issuer).await?;
+    let device_endpoint = discovery.device_authorization_endpoint
+        .ok_or_else(|| anyhow!("{} does not support the device authorization flow", issuer))?;
+
+    let auth: DeviceAuthorization = http.post(&device_endpoint)
+        .form(&[("client_id", client_id), ("scope", scope)])
+        .send().await?
+        .error_for_status()?
+        .json().await?;
+    println!("Open {} and enter the code: {}", auth.verification_uri, auth.user_code);
+    if let Some(uri) = &auth.verification_uri_complete {
+        println!("Or visit {}", uri);
+    }
+
+    let deadline = get_current_timestamp() + auth.expires_in;
+    let mut interval = auth.interval;
+    loop {
+        sleep(Duration::from_secs(interval)).await;
+        if get_current_timestamp() > deadline {
+            return Err(anyhow!("device code expired, please try again"));
+        }
+
+        let params = [
+            ("grant_type", DEVICE_CODE_GRANT),
+            ("device_code", auth.device_code.as_str()),
+            ("client_id", client_id),
+        ];
+        let resp = http.post(&discovery.token_endpoint).form(&params).send().await?;
+        match token_response(resp).await? {
+            Ok(tokens) => return Ok(tokens),
+            Err(err) if err.error == "authorization_pending" => continue,
+            Err(err) if err.error == "slow_down" => interval += 5,
+            Err(err) => return Err(anyhow!("login failed: {}", describe(&err))),
+        }
+    }
+}
+
+/// Removes the cached tokens and revokes the refresh token at the issuer.
+pub async fn logout(cfg: &Config) -> anyhow::Result<()> {
+    let session = match OidcSession::from_config(cfg) {
+        Some(session) => session,
+        None => {
+            println!("not logged in");
+            return Ok(());
+        }
+    };
+
+    // 撤销失败不影响本地登出
+    if let Err(err) = revoke(&session).await {
+        warn!("failed to revoke refresh token: {}", err);
+    }
+    config::save(&[(KEY_TOKEN, None), (KEY_EXPIRES_AT, None), (KEY_REFRESH_TOKEN, None)])?;
+    println!("logged out");
+    Ok(())
+}
+
+async fn revoke(session: &OidcSession) -> anyhow::Result<()> {
+    let discovery = discover(&session.http, &session.issuer).await?;
+    if let Some(endpoint) = discovery.revocation_endpoint {
+        let params = [
+            ("token", session.refresh_token.as_str()),
+            ("token_type_hint", "refresh_token"),
+            ("client_id", session.client_id.as_str()),
+        ];
+        session.http.post(&endpoint).form(&params).send().await?.error_for_status()?;
+        info!("refresh token revoked");
+    }
+    Ok(())
+}
+
+async fn discover(http: &reqwest::Client, issuer: &str) -> anyhow::Result<Discovery> {
+    let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+    let discovery = http.get(&url).send().await?.error_for_status()?.json().await?;
+    Ok(discovery)
+}
+
+async fn token_response(resp: reqwest::Response) -> anyhow::Result<Result<TokenResponse, TokenError>> {
+    if resp.status().is_success() {
+        return Ok(Ok(resp.json().await?));
+    }
+    let status = resp.status();
+    match resp.json::<TokenError>().await {
+        Ok(err) => Ok(Err(err)),
+        Err(_) => Err(anyhow!("token endpoint returned {}", status)),
+    }
+}
+
+fn describe(err: &TokenError) -> String {
+    match &err.error_description {
+        Some(desc) => format!("{}: {}", err.error, desc),
+        None => err.error.clone(),
+    }
+}
+
+// 优先使用ID token，其受众固定为client_id，便于服务端校验
+fn pick_token(tokens: TokenResponse) -> (String, u64) {
+    let fallback = get_current_timestamp() + tokens.expires_in.unwrap_or(300);
+    let token = tokens.id_token.unwrap_or(tokens.access_token);
+    (token.clone(), expiry(&token).unwrap_or(fallback))
+}
+
+// 只读取exp用于判断何时刷新，签名由服务端校验
+fn expiry(token: &str) -> Option<u64> {
+    let mut validation = Validation::default();
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.required_spec_claims.clear();
+    decode::<Expiry>(token, &DecodingKey::from_secret(&[]), &validation).ok().map(|data| data.claims.exp)
+}
+
+fn save(issuer: &str, client_id: &str, token: &str, expires_at: u64, refresh_token: &str) -> anyhow::Result<std::path::PathBuf> {
+    let expires_at = expires_at.to_string();
+    config::save(&[
+        (KEY_ISSUER, Some(issuer)),
+        (KEY_CLIENT_ID, Some(client_id)),
+        (KEY_TOKEN, Some(token)),
+        (KEY_EXPIRES_AT, Some(expires_at.as_str())),
+        (KEY_REFRESH_TOKEN, Some(refresh_token)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use hyper::{Body, Request, Response, StatusCode};
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+    use super::*;
+
+    const CLIENT_ID: &str = "rslocal-cli";
+
+    /// Requests the mock IdP received, as the path and the form fields.
+    type Requests = Arc<Mutex<Vec<(String, HashMap<String, String>)>>>;
+
+    fn id_token(exp: u64) -> String {
+        encode(&Header::default(), &json!({ "sub": "alice", "exp": exp }), &EncodingKey::from_secret(b"idp")).unwrap()
+    }
+
+    fn reply(status: StatusCode, body: Value) -> Response<Body> {
+        Response::builder().status(status).header("Content-Type", "application/json").body(Body::from(body.to_string())).unwrap()
+    }
+
+    // 本地模拟的IdP，设备码在被轮询pending次之后才通过授权
+    async fn mock_idp(pending: usize) -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Requests::default();
+        let pending = Arc::new(AtomicUsize::new(pending));
+        let (base, log) = (issuer.clone(), requests.clone());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (base, log, pending) = (base.clone(), log.clone(), pending.clone());
+                let service = service_fn(move |req: Request<Body>| {
+                    let (base, log, pending) = (base.clone(), log.clone(), pending.clone());
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await?;
+                        let form: HashMap<String, String> = url::form_urlencoded::parse(&body).into_owned().collect();
+                        log.lock().unwrap().push((path.clone(), form.clone()));
+                        let field = |k: &str| form.get(k).map(String::as_str).unwrap_or_default();
+                        let resp = match (path.as_str(), field("grant_type")) {
+                            ("/.well-known/openid-configuration", _) => reply(StatusCode::OK, json!({
+                                "issuer": base,
+                                "token_endpoint": format!("{}/token", base),
+                                "device_authorization_endpoint": format!("{}/device", base),
+                                "revocation_endpoint": format!("{}/revoke", base),
+                            })),
+                            ("/device", _) => reply(StatusCode::OK, json!({
+                                "device_code": "device-1", "user_code": "ABCD-EFGH",
+                                "verification_url": format!("{}/activate", base), "expires_in": 60, "interval": 0,
+                            })),
+                            ("/token", DEVICE_CODE_GRANT) if field("device_code") != "device-1" => {
+                                reply(StatusCode::BAD_REQUEST, json!({ "error": "invalid_grant" }))
+                            }
+                            ("/token", DEVICE_CODE_GRANT) if pending.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() => {
+                                reply(StatusCode::BAD_REQUEST, json!({ "error": "authorization_pending" }))
+                            }
+                            ("/token", DEVICE_CODE_GRANT) => reply(StatusCode::OK, json!({
+                                "access_token": "access-1", "id_token": id_token(4102444800), "refresh_token": "refresh-1", "expires_in": 300,
+                            })),
+                            ("/token", "refresh_token") => match field("refresh_token") {
+                                "revoked" => reply(StatusCode::BAD_REQUEST, json!({ "error": "invalid_grant", "error_description": "token was revoked" })),
+                                // 不轮换refresh token，也不返回ID token
+                                "static" => reply(StatusCode::OK, json!({ "access_token": "access-3", "expires_in": 300 })),
+                                _ => reply(StatusCode::OK, json!({ "access_token": "access-2", "id_token": id_token(4102444800), "refresh_token": "refresh-2" })),
+                            },
+                            ("/revoke", _) => reply(StatusCode::OK, json!({})),
+                            _ => reply(StatusCode::NOT_FOUND, json!({})),
+                        };
+                        Ok::<_, hyper::Error>(resp)
+                    }
+                });
+                tokio::spawn(Http::new().serve_connection(stream, service));
+            }
+        });
+        (issuer, requests)
+    }
+
+    fn session(issuer: &str, refresh_token: &str) -> OidcSession {
+        OidcSession {
+            issuer: issuer.to_string(),
+            client_id: CLIENT_ID.to_string(),
+            token: String::new(),
+            expires_at: 0,
+            refresh_token: refresh_token.to_string(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn device_flow_polls_until_authorized() {
+        let (issuer, requests) = mock_idp(2).await;
+        let tokens = device_flow(&reqwest::Client::new(), &issuer, CLIENT_ID, DEFAULT_SCOPE).await.unwrap();
+        assert_eq!(tokens.refresh_token.as_deref(), Some("refresh-1"));
+        // 优先使用ID token，过期时间取自其exp
+        let (token, expires_at) = pick_token(tokens);
+        assert_eq!(token, id_token(4102444800));
+        assert_eq!(expires_at, 4102444800);
+
+        let requests = requests.lock().unwrap();
+        let polls: Vec<_> = requests.iter().filter(|(path, _)| path == "/token").collect();
+        assert_eq!(polls.len(), 3);
+        assert!(polls.iter().all(|(_, form)| form["client_id"] == CLIENT_ID && form["device_code"] == "device-1"));
+        let device = requests.iter().find(|(path, _)| path == "/device").unwrap();
+        assert_eq!(device.1["scope"], DEFAULT_SCOPE);
+    }
+
+    #[tokio::test]
+    async fn refresh_replaces_the_tokens() {
+        let (issuer, requests) = mock_idp(0).await;
+        let mut session = session(&issuer, "refresh-1");
+        session.refresh().await.unwrap();
+        assert_eq!(session.refresh_token, "refresh-2");
+        assert_eq!(session.expires_at, 4102444800);
+        let refreshes = requests.lock().unwrap().iter().filter(|(path, _)| path == "/token").count();
+
+        // 令牌仍然有效时不再刷新
+        assert_eq!(session.token().await.unwrap(), id_token(4102444800));
+        assert_eq!(requests.lock().unwrap().iter().filter(|(path, _)| path == "/token").count(), refreshes);
+    }
+
+    #[tokio::test]
+    async fn refresh_keeps_an_unrotated_refresh_token() {
+        let (issuer, _) = mock_idp(0).await;
+        let mut session = session(&issuer, "static");
+        session.refresh().await.unwrap();
+        assert_eq!(session.token, "access-3");
+        assert_eq!(session.refresh_token, "static");
+        assert!(session.expires_at > get_current_timestamp());
+    }
+
+    #[tokio::test]
+    async fn revoked_refresh_tokens_ask_for_a_new_login() {
+        let (issuer, _) = mock_idp(0).await;
+        let err = session(&issuer, "revoked").refresh().await.unwrap_err().to_string();
+        assert!(err.contains("token was revoked") && err.contains("rslocal login"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn logout_revokes_the_refresh_token() {
+        let (issuer, requests) = mock_idp(0).await;
+        revoke(&session(&issuer, "refresh-1")).await.unwrap();
+        let requests = requests.lock().unwrap();
+        let (_, form) = requests.iter().find(|(path, _)| path == "/revoke").unwrap();
+        assert_eq!(form["token"], "refresh-1");
+        assert_eq!(form["token_type_hint"], "refresh_token");
+        assert_eq!(form["client_id"], CLIENT_ID);
+    }
+}
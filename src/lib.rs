@@ -1,8 +1,11 @@
 pub mod client;
+pub mod mux;
+pub mod protocol;
 pub mod server;
 
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use bytes::{Buf, Bytes};
 use futures_core::ready;
 use log::debug;
 use rand::{Rng, thread_rng};
@@ -18,8 +21,47 @@ fn random_string(len: usize) -> String {
     thread_rng().sample_iter(&Alphanumeric).take(len).map(char::from).collect()
 }
 
+/// A frame delivered to an [`RxReader`].
+trait Frame {
+    /// Returns the payload carried by the frame, which may be empty,
+    /// or `None` once the peer finished sending.
+    fn into_data(self) -> io::Result<Option<Bytes>>;
+}
+
+impl Frame for TransferReply {
+    fn into_data(self) -> io::Result<Option<Bytes>> {
+        match TStatus::from_i32(self.status) {
+            Some(TStatus::Working) => Ok(Some(self.req_data)),
+            Some(TStatus::Done) => Ok(None),
+            Some(TStatus::Reset) => Err(io::ErrorKind::ConnectionReset.into()),
+            Some(TStatus::Error) => Err(io::Error::other(self.reason)),
+            _ => Ok(Some(Bytes::new())),
+        }
+    }
+}
+
+impl Frame for XData {
+    fn into_data(self) -> io::Result<Option<Bytes>> {
+        match self {
+            XData::Data(data) => Ok(Some(data)),
+            XData::Eof => Ok(None),
+            XData::Reset => Err(io::ErrorKind::ConnectionReset.into()),
+            XData::Error(reason) => Err(io::Error::other(reason)),
+            XData::TX(_) => Ok(Some(Bytes::new())),
+        }
+    }
+}
+
 struct RxReader<T> {
     rx: Receiver<T>,
+    // 上一帧中调用方还没读走的数据
+    pending: Bytes,
+}
+
+impl<T> RxReader<T> {
+    fn new(rx: Receiver<T>) -> Self {
+        RxReader { rx, pending: Bytes::new() }
+    }
 }
 
 struct TxWriter<T> {
@@ -27,21 +69,25 @@ struct TxWriter<T> {
     tx: PollSender<T>,
 }
 
-impl AsyncRead for RxReader<TransferReply> {
-    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
-        debug!(":poll_read");
-
-        match ready!(self.rx.poll_recv(cx)) {
-            None => {}
-            Some(tr) => {
-                debug!("{:?}:poll_recv", tr.conn_id);
-                let data = tr.req_data;
-                debug!(":poll_read_data: {:?}", String::from_utf8_lossy(&data[..data.len().min(1024)]));
-                buf.put_slice(data.as_slice());
+impl<T: Frame> AsyncRead for RxReader<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            // 帧可能比调用方的缓冲区大，放不下的部分留到下次读取
+            if !this.pending.is_empty() {
+                let n = buf.remaining().min(this.pending.len());
+                buf.put_slice(&this.pending[..n]);
+                this.pending.advance(n);
+                debug!(":poll_read: {} bytes", n);
+                return Poll::Ready(Ok(()));
             }
-        }
 
-        Poll::Ready(Ok(()))
+            // 空帧不能当作EOF返回，继续等待下一帧
+            match ready!(this.rx.poll_recv(cx)).map(Frame::into_data).transpose()? {
+                Some(Some(data)) => this.pending = data,
+                _ => return Poll::Ready(Ok(())),
+            }
+        }
     }
 }
 
@@ -52,20 +98,20 @@ impl AsyncWrite for TxWriter<TransferBody> {
             return Poll::Ready(Ok(0));
         }
 
-        match ready!(self.tx.poll_reserve(cx)) {
-            Ok(_) => {
-                let conn_id = self.conn_id.clone();
-                let result = self.tx.send_item(TransferBody {
-                    conn_id,
-                    status: TStatus::Working as i32,
-                    resp_data: buf.to_vec(),
-                });
-                if let Err(err) = result {
-                    debug!("{:?}:poll_write err: {}", self.conn_id, err.to_string());
-                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err)));
-                }
-            }
-            Err(_) => {}
+        // 对端已关闭时不能假装写入成功
+        if ready!(self.tx.poll_reserve(cx)).is_err() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let conn_id = self.conn_id.clone();
+        let result = self.tx.send_item(TransferBody {
+            conn_id,
+            status: TStatus::Working as i32,
+            resp_data: Bytes::copy_from_slice(buf),
+            ..Default::default()
+        });
+        if let Err(err) = result {
+            debug!("{:?}:poll_write err: {}", self.conn_id, err.to_string());
+            return Poll::Ready(Err(io::Error::other(err)));
         }
 
         Poll::Ready(Ok(buf.len()))
@@ -77,60 +123,37 @@ impl AsyncWrite for TxWriter<TransferBody> {
 
     fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
         debug!("{:?}:poll_shutdown", self.conn_id);
-        match ready!(self.tx.poll_reserve(cx)) {
-            Ok(_) => {
-                let conn_id = self.conn_id.clone();
-                let result = self.tx.send_item(TransferBody {
-                    conn_id,
-                    status: TStatus::Done as i32,
-                    resp_data: vec![],
-                });
-                if let Err(err) = result {
-                    debug!("{:?}:poll_shutdown err: {}", self.conn_id, err.to_string());
-                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err)));
-                }
-            }
-            Err(_) => {}
-        }
-        Poll::Ready(Ok(()))
-    }
-}
-
-// todo 如何使用泛型进一步优化？
-impl AsyncRead for RxReader<XData> {
-    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
-        debug!(":poll_read");
-
-        match ready!(self.rx.poll_recv(cx)) {
-            None => {}
-            Some(tr) => {
-                if let XData::Data(data) = tr {
-                    debug!(":poll_read_data: {:?}", String::from_utf8_lossy(&data[..data.len().min(20)]));
-                    buf.put_slice(data.as_slice());
-                }
+        if ready!(self.tx.poll_reserve(cx)).is_ok() {
+            let conn_id = self.conn_id.clone();
+            let result = self.tx.send_item(TransferBody {
+                conn_id,
+                status: TStatus::Done as i32,
+                resp_data: Bytes::new(),
+                ..Default::default()
+            });
+            if let Err(err) = result {
+                debug!("{:?}:poll_shutdown err: {}", self.conn_id, err.to_string());
+                return Poll::Ready(Err(io::Error::other(err)));
             }
         }
-
         Poll::Ready(Ok(()))
     }
 }
 
-impl AsyncWrite for TxWriter<Vec<u8>> {
+impl AsyncWrite for TxWriter<XData> {
     fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, std::io::Error>> {
         debug!("{:?}:poll_write:{}", self.conn_id, buf.len());
         if buf.is_empty() {
             return Poll::Ready(Ok(0));
         }
 
-        match ready!(self.tx.poll_reserve(cx)) {
-            Ok(_) => {
-                let result = self.tx.send_item(Vec::from(buf));
-                if let Err(err) = result {
-                    debug!("{:?}:poll_write err: {}", self.conn_id, err.to_string());
-                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err)));
-                }
-            }
-            Err(_) => {}
+        if ready!(self.tx.poll_reserve(cx)).is_err() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let result = self.tx.send_item(XData::Data(Bytes::copy_from_slice(buf)));
+        if let Err(err) = result {
+            debug!("{:?}:poll_write err: {}", self.conn_id, err.to_string());
+            return Poll::Ready(Err(io::Error::other(err)));
         }
 
         Poll::Ready(Ok(buf.len()))
@@ -142,16 +165,120 @@ impl AsyncWrite for TxWriter<Vec<u8>> {
 
     fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
         debug!("{:?}:poll_shutdown", self.conn_id);
-        match ready!(self.tx.poll_reserve(cx)) {
-            Ok(_) => {
-                let result = self.tx.send_item(vec![]);
-                if let Err(err) = result {
-                    debug!("{:?}:poll_shutdown err: {}", self.conn_id, err.to_string());
-                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err)));
-                }
+        if ready!(self.tx.poll_reserve(cx)).is_ok() {
+            let result = self.tx.send_item(XData::Eof);
+            if let Err(err) = result {
+                debug!("{:?}:poll_shutdown err: {}", self.conn_id, err.to_string());
+                return Poll::Ready(Err(io::Error::other(err)));
             }
-            Err(_) => {}
         }
         Poll::Ready(Ok(()))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use tokio::io::AsyncReadExt;
+    use tokio::sync::mpsc;
+    use super::*;
+
+    // 在随机位置切分数据，其中夹杂空帧
+    fn fragment(data: &[u8], rng: &mut StdRng) -> Vec<Bytes> {
+        let mut frames = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            if rng.gen_bool(0.2) {
+                frames.push(Bytes::new());
+            }
+            let n = rng.gen_range(1..=rest.len().min(4096));
+            frames.push(Bytes::copy_from_slice(&rest[..n]));
+            rest = &rest[n..];
+        }
+        frames
+    }
+
+    async fn read_all<T: Frame>(reader: &mut RxReader<T>, rng: &mut StdRng) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        loop {
+            let mut buf = vec![0u8; rng.gen_range(1..=8192)];
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(out);
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    #[tokio::test]
+    async fn reassembles_fragmented_frames() {
+        let mut rng = StdRng::seed_from_u64(32);
+        for round in 0..50 {
+            let data: Vec<u8> = (0..rng.gen_range(0..64 * 1024)).map(|_| rng.gen()).collect();
+            let frames = fragment(&data, &mut rng);
+            let (tx, rx) = mpsc::channel(frames.len() + 1);
+            for frame in frames {
+                tx.send(XData::Data(frame)).await.unwrap();
+            }
+            tx.send(XData::Eof).await.unwrap();
+
+            let got = read_all(&mut RxReader::new(rx), &mut rng).await.unwrap();
+            assert_eq!(got, data, "round {}", round);
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_frames_without_payload() {
+        let mut rng = StdRng::seed_from_u64(33);
+        let data: Vec<u8> = (0..10_000).map(|_| rng.gen()).collect();
+        let (tx, rx) = mpsc::channel(1024);
+        for frame in fragment(&data, &mut rng) {
+            // 窗口帧不带数据，不能被当作EOF
+            tx.send(TransferReply { status: TStatus::Window as i32, window: 1, ..Default::default() }).await.unwrap();
+            tx.send(TransferReply { status: TStatus::Working as i32, req_data: frame, ..Default::default() }).await.unwrap();
+        }
+        tx.send(TransferReply { status: TStatus::Done as i32, ..Default::default() }).await.unwrap();
+
+        let got = read_all(&mut RxReader::new(rx), &mut rng).await.unwrap();
+        assert_eq!(got, data);
+    }
+
+    #[tokio::test]
+    async fn frame_larger_than_the_read_buffer() {
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(XData::Data(Bytes::from_static(b"hello world"))).await.unwrap();
+        tx.send(XData::Eof).await.unwrap();
+
+        let mut reader = RxReader::new(rx);
+        let mut buf = [0u8; 4];
+        let mut got = Vec::new();
+        for expected in [4, 4, 3, 0] {
+            let n = reader.read(&mut buf).await.unwrap();
+            assert_eq!(n, expected);
+            got.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(got, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn reset_after_data_is_an_error() {
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(XData::Data(Bytes::from_static(b"partial"))).await.unwrap();
+        tx.send(XData::Reset).await.unwrap();
+
+        let mut reader = RxReader::new(rx);
+        let mut got = Vec::new();
+        let err = reader.read_to_end(&mut got).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(got, b"partial");
+    }
+
+    #[tokio::test]
+    async fn closed_channel_is_eof() {
+        let (tx, rx) = mpsc::channel::<XData>(1);
+        drop(tx);
+        let mut got = Vec::new();
+        assert_eq!(RxReader::new(rx).read_to_end(&mut got).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,234 @@
+//! Multiplexing of visitor connections over the single `Transfer` stream of a tunnel.
+//!
+//! Every frame is tagged with the connection it belongs to. Each direction of a
+//! connection has its own credit-based send window: the sender spends credit for
+//! every payload byte and the receiver hands it back with a `Window` frame once
+//! the bytes were delivered, so one slow connection cannot stall the others.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use bytes::{Bytes, BytesMut};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::Semaphore;
+
+/// Metadata key carrying the tunnel id when the client opens the `Transfer` stream.
+pub const TUNNEL_ID_KEY: &str = "x-tunnel-id";
+
+/// Credit each direction of a connection starts with.
+pub const INITIAL_WINDOW: usize = 256 * 1024;
+
+// 限制单帧大小，避免一个连接的大块数据长时间占用复用流
+pub const MAX_FRAME_SIZE: usize = 32 * 1024;
+
+/// Send window of one direction of a connection.
+#[derive(Debug, Clone)]
+pub struct Window {
+    credit: Arc<Semaphore>,
+}
+
+impl Default for Window {
+    fn default() -> Self {
+        Window { credit: Arc::new(Semaphore::new(INITIAL_WINDOW)) }
+    }
+}
+
+impl Window {
+    /// Waits until `n` bytes may be sent. Returns false once the window was closed.
+    pub async fn acquire(&self, n: usize) -> bool {
+        match self.credit.acquire_many(n as u32).await {
+            Ok(permit) => {
+                permit.forget();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Hands back credit announced by the receiver, never more than the initial window.
+    pub fn release(&self, n: usize) {
+        // 对端多报的额度直接忽略，避免窗口无限增长
+        let room = INITIAL_WINDOW.saturating_sub(self.credit.available_permits());
+        self.credit.add_permits(n.min(room));
+    }
+
+    /// Wakes up pending senders once the connection is gone.
+    pub fn close(&self) {
+        self.credit.close();
+    }
+}
+
+/// Receive side of a window, counts the bytes the peer sent and has not got credit back for.
+#[derive(Debug, Clone, Default)]
+pub struct RecvWindow {
+    pending: Arc<AtomicUsize>,
+}
+
+impl RecvWindow {
+    /// Accounts for `n` received bytes. Returns false if the peer sent more than its credit.
+    pub fn consume(&self, n: usize) -> bool {
+        self.pending.fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| {
+            pending.checked_add(n).filter(|total| *total <= INITIAL_WINDOW)
+        }).is_ok()
+    }
+
+    /// Gives `n` bytes of credit back, call it before announcing them with a `Window` frame.
+    pub fn release(&self, n: usize) {
+        let _ = self.pending.fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| Some(pending.saturating_sub(n)));
+    }
+}
+
+/// Appends the payloads already queued behind `first` while the frame is not
+/// full, so a burst of small writes travels as one frame. `payload` extracts
+/// the data of a queued item; the first item without data is handed back and
+/// must be processed after the merged payload.
+pub fn coalesce<T>(first: Bytes, rx: &mut Receiver<T>, payload: impl Fn(T) -> Result<Bytes, T>) -> (Bytes, Option<T>) {
+    let mut merged: Option<BytesMut> = None;
+    let mut rest = None;
+    let mut len = first.len();
+    while len < MAX_FRAME_SIZE {
+        let item = match rx.try_recv() {
+            Ok(item) => item,
+            Err(_) => break,
+        };
+        match payload(item) {
+            Ok(data) => {
+                len += data.len();
+                merged.get_or_insert_with(|| BytesMut::from(&first[..])).extend_from_slice(&data);
+            }
+            Err(item) => {
+                rest = Some(item);
+                break;
+            }
+        }
+    }
+
+    (merged.map(BytesMut::freeze).unwrap_or(first), rest)
+}
+
+/// Cuts a payload into frames of at most [`MAX_FRAME_SIZE`] without copying.
+pub fn split(mut data: Bytes) -> impl Iterator<Item=Bytes> {
+    std::iter::from_fn(move || {
+        if data.is_empty() {
+            return None;
+        }
+        Some(data.split_to(data.len().min(MAX_FRAME_SIZE)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use tokio::sync::mpsc;
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Item {
+        Data(Bytes),
+        Other,
+    }
+
+    fn random_bytes(rng: &mut StdRng, max: usize) -> Bytes {
+        let mut data = vec![0u8; rng.gen_range(0..=max)];
+        rng.fill(&mut data[..]);
+        data.into()
+    }
+
+    #[test]
+    fn coalesce_and_split_keep_every_byte_in_order() {
+        let mut rng = StdRng::seed_from_u64(36);
+        for _ in 0..100 {
+            let (tx, mut rx) = mpsc::channel(1024);
+            let mut sent = Vec::new();
+            let mut others = 0;
+            for _ in 0..rng.gen_range(0..64) {
+                if rng.gen_bool(0.1) {
+                    tx.try_send(Item::Other).unwrap();
+                    others += 1;
+                } else {
+                    // 大多是小块写入，偶尔有超过一帧的数据
+                    let max = if rng.gen_bool(0.2) { 3 * MAX_FRAME_SIZE } else { 512 };
+                    let data = random_bytes(&mut rng, max);
+                    sent.extend_from_slice(&data);
+                    tx.try_send(Item::Data(data)).unwrap();
+                }
+            }
+            drop(tx);
+
+            // 按发送端的方式取出、合并并切分成帧
+            let mut received = Vec::new();
+            let mut seen_others = 0;
+            let mut next = rx.try_recv().ok();
+            while let Some(item) = next.take() {
+                let first = match item {
+                    Item::Data(data) => data,
+                    Item::Other => {
+                        seen_others += 1;
+                        next = rx.try_recv().ok();
+                        continue;
+                    }
+                };
+                let (merged, rest) = coalesce(first, &mut rx, |item| match item {
+                    Item::Data(data) => Ok(data),
+                    item => Err(item),
+                });
+                for chunk in split(merged) {
+                    assert!(!chunk.is_empty() && chunk.len() <= MAX_FRAME_SIZE);
+                    received.extend_from_slice(&chunk);
+                }
+                next = rest.or_else(|| rx.try_recv().ok());
+            }
+            assert_eq!(received, sent);
+            assert_eq!(seen_others, others);
+        }
+    }
+
+    #[test]
+    fn window_credit_never_exceeds_the_initial_window() {
+        let mut rng = StdRng::seed_from_u64(37);
+        let window = Window::default();
+        let mut credit = INITIAL_WINDOW;
+        for _ in 0..10_000 {
+            if rng.gen_bool(0.5) {
+                let n = rng.gen_range(0..=MAX_FRAME_SIZE);
+                let acquired = window.acquire(n).now_or_never();
+                assert_eq!(acquired.is_some(), n <= credit);
+                if acquired.is_some() {
+                    credit -= n;
+                } else {
+                    // 等待中的acquire被取消，额度不受影响
+                    continue;
+                }
+            } else {
+                let n = rng.gen_range(0..=2 * MAX_FRAME_SIZE);
+                window.release(n);
+                credit = (credit + n).min(INITIAL_WINDOW);
+            }
+            assert_eq!(window.credit.available_permits(), credit);
+        }
+
+        window.close();
+        assert_eq!(window.acquire(1).now_or_never(), Some(false));
+    }
+
+    #[test]
+    fn recv_window_rejects_overruns() {
+        let mut rng = StdRng::seed_from_u64(38);
+        let window = RecvWindow::default();
+        let mut pending = 0;
+        for _ in 0..10_000 {
+            let n = rng.gen_range(0..=MAX_FRAME_SIZE);
+            if rng.gen_bool(0.6) {
+                assert_eq!(window.consume(n), pending + n <= INITIAL_WINDOW);
+                if pending + n <= INITIAL_WINDOW {
+                    pending += n;
+                }
+            } else {
+                window.release(n);
+                pending = pending.saturating_sub(n);
+            }
+            assert_eq!(window.pending.load(Ordering::Acquire), pending);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+//! Versioning and capability negotiation of the control protocol.
+//!
+//! Both sides announce the version they speak and the optional features they
+//! support. A peer older than [`MIN_PROTOCOL_VERSION`] is refused; anything
+//! else is accepted and only the capabilities known to both sides are used.
+
+/// Version of the control protocol spoken by this build.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest peer version this build can still talk to.
+/// Version 2 multiplexes all connections of a tunnel over one stream.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// The server attaches visitor metadata to new connection events.
+pub const CAP_VISITOR_METADATA: &str = "visitor-metadata";
+
+/// The server pings the client on the control stream and expects pongs.
+pub const CAP_HEARTBEAT: &str = "heartbeat";
+
+/// Capabilities supported by this build.
+pub const CAPABILITIES: &[&str] = &[CAP_VISITOR_METADATA, CAP_HEARTBEAT];
+
+pub fn capabilities() -> Vec<String> {
+    CAPABILITIES.iter().map(|c| c.to_string()).collect()
+}
+
+/// Checks whether a peer speaking `version` is compatible with this build.
+/// `peer` names the other side in the error message, e.g. "server".
+pub fn check_version(peer: &str, version: u32) -> Result<(), String> {
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(format!("{} speaks protocol version {}, but at least {} is required, please upgrade the {}",
+                           peer, version, MIN_PROTOCOL_VERSION, peer));
+    }
+
+    Ok(())
+}
+
+/// Returns the capabilities supported by both this build and the peer.
+pub fn negotiate(peer: &[String]) -> Vec<String> {
+    peer.iter().filter(|c| CAPABILITIES.contains(&c.as_str())).cloned().collect()
+}
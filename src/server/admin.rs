@@ -0,0 +1,384 @@
+use jsonwebtoken::get_current_timestamp;
+use log::{info, warn};
+use tonic::{Request, Response, Status};
+use crate::server::api;
+use crate::server::api::admin_server::Admin;
+use crate::server::api::{CloseTunnelParam, CreateTokenParam, CreateTokenReply, ReloadReply, ReservationList, RevokeReply, RevokeSessionParam, RevokeTokenParam, SessionInfo, SessionList, TokenInfo, TokenList, TunnelList};
+use crate::server::config::{PolicyConfig, TokenConfig, TokenEntry};
+use crate::server::{AuthError, Credentials, generate_token, hash_token, HashAlgorithm, Identity, is_dns_label, parse_expiry, parse_port_range, Reservation, SCOPE_ADMIN, SCOPES, too_many_attempts, Tunnel};
+
+/// The management API, callers present the token of an administrator on every call.
+#[derive(Clone)]
+pub struct RSLAdmin {
+    tunnel: Tunnel,
+}
+
+impl RSLAdmin {
+    pub fn new(tunnel: Tunnel) -> Self {
+        RSLAdmin { tunnel }
+    }
+
+    // 每次调用都校验令牌，只有带admin scope的令牌或管理员组的成员可以使用
+    async fn authorize<T: Send + Sync>(&self, req: &Request<T>) -> Result<Identity, Status> {
+        let token = req.metadata().get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("missing bearer token"))?;
+        let addr = req.remote_addr();
+        let ip = addr.map(|addr| addr.ip());
+        let limiter = self.tunnel.limiter();
+        limiter.check(ip).map_err(too_many_attempts)?;
+
+        let creds = Credentials { token: token.to_string(), peer_certs: req.peer_certs() };
+        let identity = match self.tunnel.auth().authenticate(&creds).await {
+            Ok(identity) => identity,
+            Err(AuthError::Rejected(msg)) => {
+                warn!("admin login from {} failed: {}", addr.map_or("unknown address".to_string(), |a| a.to_string()), msg);
+                limiter.failed(ip);
+                return Err(Status::unauthenticated(msg));
+            }
+            Err(AuthError::Unavailable(msg)) => return Err(Status::unavailable(msg)),
+        };
+        limiter.succeeded(ip);
+
+        let groups = self.tunnel.config().admin.as_ref().map(|admin| admin.groups.clone()).unwrap_or_default();
+        let granted = identity.has_scope(SCOPE_ADMIN) || identity.groups.iter().any(|g| groups.contains(g));
+        if !granted {
+            return Err(Status::permission_denied(format!("user {} is not an administrator", identity.username)));
+        }
+        Ok(identity)
+    }
+
+    // 端口要在允许的范围内，其他用户正在使用的子域名和端口也不能预留
+    #[allow(clippy::result_large_err)]
+    fn check_reservation(&self, reservation: &Reservation) -> Result<(), Status> {
+        let cfg = self.tunnel.config();
+        let entrypoint = match (&reservation.subdomain, reservation.port) {
+            (Some(subdomain), _) => {
+                if !is_dns_label(subdomain) {
+                    return Err(Status::invalid_argument(format!("subdomain {} is not a valid DNS label", subdomain)));
+                }
+                format!("http://{}.{}", subdomain, cfg.http.default_domain).to_lowercase()
+            }
+            (None, Some(port)) => {
+                let mut ranges = std::iter::once(parse_port_range(&cfg.core.allow_ports))
+                    .chain(cfg.policies.iter().map(PolicyConfig::port_range))
+                    .flatten();
+                if !ranges.any(|(min, max)| (min..=max).contains(&port)) {
+                    return Err(Status::invalid_argument(format!("port {} is outside core.allow_ports and the ports of every policy", port)));
+                }
+                format!("tcp://0.0.0.0:{}", port)
+            }
+            (None, None) => return Ok(()),
+        };
+        let holder = self.tunnel.tunnels().list().into_iter()
+            .find(|t| t.entrypoint == entrypoint && t.username != reservation.username);
+        if let Some(tunnel) = holder {
+            return Err(Status::failed_precondition(format!("{} is in use by a tunnel of {}, close it first", reservation, tunnel.username)));
+        }
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for RSLAdmin {
+    async fn reload(&self, request: Request<()>) -> Result<Response<ReloadReply>, Status> {
+        let admin = self.authorize(&request).await?;
+        let summary = self.tunnel.reload().await.map_err(|e| {
+            warn!("config reload by {} rejected: {:#}", admin.username, e);
+            Status::failed_precondition(format!("{:#}", e))
+        })?;
+        info!("config reloaded by {}: {}", admin.username, summary);
+        Ok(Response::new(ReloadReply {
+            changes: summary.changes,
+            restart_required: summary.restart_required,
+            revoked_sessions: summary.revoked_sessions as u32,
+        }))
+    }
+
+    async fn list_tunnels(&self, request: Request<()>) -> Result<Response<TunnelList>, Status> {
+        self.authorize(&request).await?;
+        Ok(Response::new(TunnelList { tunnels: self.tunnel.tunnels().list() }))
+    }
+
+    async fn close_tunnel(&self, request: Request<CloseTunnelParam>) -> Result<Response<()>, Status> {
+        let admin = self.authorize(&request).await?;
+        let id = request.into_inner().id;
+        if !self.tunnel.tunnels().close(&id, "tunnel closed by an administrator").await {
+            return Err(Status::not_found(format!("tunnel {} not found", id)));
+        }
+        info!("tunnel {} closed by {}", id, admin.username);
+        Ok(Response::new(()))
+    }
+
+    async fn list_sessions(&self, request: Request<()>) -> Result<Response<SessionList>, Status> {
+        self.authorize(&request).await?;
+        let sessions = self.tunnel.sessions().list().iter().map(|session| SessionInfo {
+            id: session.id().to_string(),
+            username: session.identity().username.clone(),
+            groups: session.identity().groups.clone(),
+            created_at: session.created_at(),
+            expires_at: session.expires_at().unwrap_or_default(),
+            tunnels: session.tunnels() as u32,
+        }).collect();
+        Ok(Response::new(SessionList { sessions }))
+    }
+
+    async fn revoke_session(&self, request: Request<RevokeSessionParam>) -> Result<Response<RevokeReply>, Status> {
+        let admin = self.authorize(&request).await?;
+        let param = request.into_inner();
+        let sessions = self.tunnel.sessions();
+        let revoked = match (param.id.is_empty(), param.username.is_empty()) {
+            (false, true) if sessions.revoke(&param.id) => 1,
+            (false, true) => return Err(Status::not_found(format!("session {} not found", param.id))),
+            (true, false) => sessions.revoke_user(&param.username),
+            _ => return Err(Status::invalid_argument("either id or username is required")),
+        };
+        info!("{} sessions revoked by {}", revoked, admin.username);
+        Ok(Response::new(RevokeReply { revoked_sessions: revoked as u32 }))
+    }
+
+    async fn list_reservations(&self, request: Request<()>) -> Result<Response<ReservationList>, Status> {
+        self.authorize(&request).await?;
+        let reservations = self.tunnel.state().reservations.iter().map(|r| api::Reservation {
+            subdomain: r.subdomain.clone().unwrap_or_default(),
+            port: r.port.unwrap_or_default() as u32,
+            username: r.username.clone(),
+        }).collect();
+        Ok(Response::new(ReservationList { reservations }))
+    }
+
+    async fn reserve(&self, request: Request<api::Reservation>) -> Result<Response<()>, Status> {
+        let admin = self.authorize(&request).await?;
+        let reservation = parse_reservation(request.into_inner())?;
+        if reservation.username.is_empty() {
+            return Err(Status::invalid_argument("username is required"));
+        }
+        self.check_reservation(&reservation)?;
+        #[allow(clippy::result_large_err)]
+        let reservation = self.tunnel.update_state(move |state| {
+            let owner = match (&reservation.subdomain, reservation.port) {
+                (Some(subdomain), _) => state.subdomain_owner(subdomain),
+                (None, port) => port.and_then(|port| state.port_owner(port)),
+            };
+            if let Some(owner) = owner {
+                return Err(Status::already_exists(format!("{} is already reserved for {}", reservation, owner)));
+            }
+            state.reservations.push(reservation.clone());
+            Ok(reservation)
+        }).await?;
+        info!("{} reserved for {} by {}", reservation, reservation.username, admin.username);
+        Ok(Response::new(()))
+    }
+
+    async fn release(&self, request: Request<api::Reservation>) -> Result<Response<()>, Status> {
+        let admin = self.authorize(&request).await?;
+        let wanted = parse_reservation(request.into_inner())?;
+        #[allow(clippy::result_large_err)]
+        let released = self.tunnel.update_state(move |state| {
+            let i = state.reservations.iter()
+                .position(|r| r.subdomain == wanted.subdomain && r.port == wanted.port)
+                .ok_or_else(|| Status::not_found(format!("{} is not reserved", wanted)))?;
+            Ok(state.reservations.remove(i))
+        }).await?;
+        info!("{} of {} released by {}", released, released.username, admin.username);
+        Ok(Response::new(()))
+    }
+
+    async fn list_tokens(&self, request: Request<()>) -> Result<Response<TokenList>, Status> {
+        self.authorize(&request).await?;
+        let cfg = self.tunnel.config();
+        let state = self.tunnel.state();
+        let mut tokens: Vec<TokenInfo> = cfg.tokens.iter()
+            .map(|(username, entry)| token_info(username, entry, false))
+            .chain(state.tokens.iter()
+                .filter(|(username, _)| !cfg.tokens.contains_key(*username))
+                .map(|(username, entry)| token_info(username, entry, true)))
+            .collect();
+        tokens.sort_by(|a, b| a.username.cmp(&b.username));
+        Ok(Response::new(TokenList { tokens }))
+    }
+
+    async fn create_token(&self, request: Request<CreateTokenParam>) -> Result<Response<CreateTokenReply>, Status> {
+        let admin = self.authorize(&request).await?;
+        let param = request.into_inner();
+        let username = param.username;
+        if username.is_empty() {
+            return Err(Status::invalid_argument("username is required"));
+        }
+        let cfg = self.tunnel.config();
+        if !cfg.core.auth_methods().contains(&"token") {
+            return Err(Status::failed_precondition("token is not listed in core.auth_method"));
+        }
+        if let Some(scope) = param.scopes.iter().find(|s| !SCOPES.contains(&s.as_str())) {
+            return Err(Status::invalid_argument(format!("unknown scope {}, expected one of {}", scope, SCOPES.join(", "))));
+        }
+        let expires = Some(param.expires).filter(|e| !e.is_empty());
+        let expires_at = expires.as_deref().map(parse_expiry).transpose()
+            .map_err(|e| Status::invalid_argument(format!("{:#}", e)))?;
+        if expires_at.is_some_and(|at| at <= get_current_timestamp()) {
+            return Err(Status::invalid_argument("expiry is in the past"));
+        }
+
+        // 只保存摘要，令牌只在这里返回一次
+        let token = generate_token();
+        let entry = TokenEntry::Detailed(TokenConfig {
+            token: hash_token(&token, HashAlgorithm::Sha256).map_err(|e| Status::internal(format!("{:#}", e)))?,
+            expires,
+            description: Some(param.description).filter(|d| !d.is_empty()),
+            scopes: Some(param.scopes).filter(|s| !s.is_empty()),
+        });
+        let user = username.clone();
+        #[allow(clippy::result_large_err)]
+        self.tunnel.update_state(move |state| {
+            if cfg.tokens.contains_key(&user) || state.tokens.contains_key(&user) {
+                return Err(Status::already_exists(format!("user {} already has a token", user)));
+            }
+            state.tokens.insert(user, entry);
+            Ok(())
+        }).await?;
+        info!("token of user {} created by {}", username, admin.username);
+        Ok(Response::new(CreateTokenReply { token, expires_at: expires_at.unwrap_or_default() }))
+    }
+
+    async fn revoke_token(&self, request: Request<RevokeTokenParam>) -> Result<Response<RevokeReply>, Status> {
+        let admin = self.authorize(&request).await?;
+        let username = request.into_inner().username;
+        let cfg = self.tunnel.config();
+        let user = username.clone();
+        #[allow(clippy::result_large_err)]
+        self.tunnel.update_state(move |state| {
+            if state.tokens.remove(&user).is_some() {
+                return Ok(());
+            }
+            if cfg.tokens.contains_key(&user) {
+                return Err(Status::failed_precondition(format!("token of user {} is defined in the config file", user)));
+            }
+            Err(Status::not_found(format!("user {} has no token", user)))
+        }).await?;
+        let revoked = self.tunnel.sessions().revoke_user(&username);
+        info!("token of user {} revoked by {}, {} sessions ended", username, admin.username, revoked);
+        Ok(Response::new(RevokeReply { revoked_sessions: revoked as u32 }))
+    }
+}
+
+// 子域名和端口二选一
+#[allow(clippy::result_large_err)]
+fn parse_reservation(r: api::Reservation) -> Result<Reservation, Status> {
+    let (subdomain, port) = match (r.subdomain.is_empty(), r.port) {
+        (false, 0) => (Some(r.subdomain.to_lowercase()), None),
+        (true, port) if port > 0 => {
+            let port = u16::try_from(port).map_err(|_| Status::invalid_argument(format!("invalid port {}", port)))?;
+            (None, Some(port))
+        }
+        _ => return Err(Status::invalid_argument("either subdomain or port is required")),
+    };
+    Ok(Reservation { username: r.username, subdomain, port })
+}
+
+fn token_info(username: &str, entry: &TokenEntry, managed: bool) -> TokenInfo {
+    let mut info = TokenInfo { username: username.to_string(), managed, ..Default::default() };
+    if let TokenEntry::Detailed(cfg) = entry {
+        info.description = cfg.description.clone().unwrap_or_default();
+        info.expires_at = cfg.expires.as_deref().and_then(|e| parse_expiry(e).ok()).unwrap_or_default();
+        info.scopes = cfg.scopes.clone().unwrap_or_default();
+    }
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tonic::Code;
+    use crate::server::{Config, StaticTokens};
+    use crate::server::api::TunnelInfo;
+    use super::*;
+
+    const ROOT: &str = "rslocald_Adm1nTok3nXyZ9qW";
+    const ALICE: &str = "rslocald_Pz3xLq8VwN5tKe2M";
+
+    fn admin() -> RSLAdmin {
+        let cfg: Config = serde_json::from_value(json!({
+            "core": { "debug": false, "bind_addr": "127.0.0.1:0", "auth_method": "token", "allow_ports": "18000-18100" },
+            "http": { "bind_addr": "127.0.0.1:0", "default_domain": "localtest.me" },
+            "admin": { "bind_addr": "127.0.0.1:0" },
+            "policies": [{ "users": ["bob"], "ports": "20000-20010" }],
+            "tokens": { "root": { "token": ROOT, "scopes": ["admin"] }, "alice": ALICE },
+        })).unwrap();
+        let auth = StaticTokens::new(cfg.tokens.clone()).unwrap();
+        RSLAdmin::new(Tunnel::new(cfg).with_authenticator(auth))
+    }
+
+    fn request<T>(token: &str, msg: T) -> Request<T> {
+        let mut req = Request::new(msg);
+        req.metadata_mut().insert("authorization", format!("Bearer {}", token).parse().unwrap());
+        req
+    }
+
+    async fn reserve(admin: &RSLAdmin, username: &str, subdomain: &str, port: u32) -> Result<(), Code> {
+        let reservation = api::Reservation { username: username.to_string(), subdomain: subdomain.to_string(), port };
+        admin.reserve(request(ROOT, reservation)).await.map(|_| ()).map_err(|s| s.code())
+    }
+
+    fn live_tunnel(admin: &RSLAdmin, username: &str, entrypoint: &str) {
+        admin.tunnel.tunnels().insert_idle(TunnelInfo {
+            id: format!("{}-{}", username, entrypoint),
+            entrypoint: entrypoint.to_string(),
+            username: username.to_string(),
+            ..Default::default()
+        });
+    }
+
+    #[tokio::test]
+    async fn only_administrators_are_authorized() {
+        let admin = admin();
+        assert!(admin.reload(Request::new(())).await.is_err());
+        assert_eq!(admin.list_tunnels(request(ALICE, ())).await.unwrap_err().code(), Code::PermissionDenied);
+        assert_eq!(admin.list_tunnels(request("rslocald_Unkn0wnT0kenXyZ", ())).await.unwrap_err().code(), Code::Unauthenticated);
+        assert!(admin.list_tunnels(request(ROOT, ())).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn reserved_ports_must_be_allowed() {
+        let admin = admin();
+        assert_eq!(reserve(&admin, "alice", "", 18000).await, Ok(()));
+        assert_eq!(reserve(&admin, "alice", "", 18100).await, Ok(()));
+        // 任一策略允许的端口也可以预留
+        assert_eq!(reserve(&admin, "bob", "", 20010).await, Ok(()));
+        assert_eq!(reserve(&admin, "alice", "", 17999).await, Err(Code::InvalidArgument));
+        assert_eq!(reserve(&admin, "alice", "", 20011).await, Err(Code::InvalidArgument));
+        assert_eq!(reserve(&admin, "alice", "", 70000).await, Err(Code::InvalidArgument));
+        assert_eq!(reserve(&admin, "alice", "bad.name", 0).await, Err(Code::InvalidArgument));
+        assert_eq!(reserve(&admin, "alice", "demo", 18001).await, Err(Code::InvalidArgument));
+        assert_eq!(reserve(&admin, "", "demo", 0).await, Err(Code::InvalidArgument));
+    }
+
+    #[tokio::test]
+    async fn reservations_do_not_take_live_tunnels() {
+        let admin = admin();
+        live_tunnel(&admin, "bob", "tcp://0.0.0.0:18001");
+        live_tunnel(&admin, "bob", "http://demo.localtest.me");
+        assert_eq!(reserve(&admin, "alice", "", 18001).await, Err(Code::FailedPrecondition));
+        assert_eq!(reserve(&admin, "alice", "Demo", 0).await, Err(Code::FailedPrecondition));
+        // 隧道的所有者可以预留它正在使用的入口
+        assert_eq!(reserve(&admin, "bob", "", 18001).await, Ok(()));
+        assert_eq!(reserve(&admin, "bob", "demo", 0).await, Ok(()));
+        assert!(admin.tunnel.state().reservations.iter().all(|r| r.username == "bob"));
+    }
+
+    #[tokio::test]
+    async fn duplicate_reservations_are_rejected() {
+        let admin = admin();
+        assert_eq!(reserve(&admin, "alice", "demo", 0).await, Ok(()));
+        assert_eq!(reserve(&admin, "bob", "DEMO", 0).await, Err(Code::AlreadyExists));
+        assert_eq!(reserve(&admin, "alice", "demo", 0).await, Err(Code::AlreadyExists));
+        assert_eq!(reserve(&admin, "alice", "", 18002).await, Ok(()));
+        assert_eq!(reserve(&admin, "bob", "", 18002).await, Err(Code::AlreadyExists));
+
+        let release = api::Reservation { subdomain: "demo".to_string(), ..Default::default() };
+        admin.release(request(ROOT, release.clone())).await.unwrap();
+        assert_eq!(admin.release(request(ROOT, release)).await.unwrap_err().code(), Code::NotFound);
+        assert_eq!(reserve(&admin, "bob", "demo", 0).await, Ok(()));
+        assert_eq!(admin.tunnel.state().subdomain_owner("demo"), Some("bob"));
+    }
+}
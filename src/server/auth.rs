@@ -0,0 +1,465 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use jsonwebtoken::get_current_timestamp;
+use log::{debug, info, warn};
+use reqwest::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use tonic::transport::Certificate;
+use crate::server::{Config, OidcError, OidcVerifier, StoredToken};
+use crate::server::token;
+use crate::server::config::{CalloutConfig, TokenConfig, TokenEntry};
+
+/// The user behind a login, as reported by an [`Authenticator`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    pub username: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Extra information about the user, e.g. email or display name.
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    /// Scopes granted to the login, all but `admin` when `None`.
+    #[serde(default)]
+    pub scopes: Option<Vec<String>>,
+    /// Unix time the credentials expire at, sessions do not outlive it.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+impl Identity {
+    pub fn new(username: impl Into<String>) -> Self {
+        Identity { username: username.into(), ..Default::default() }
+    }
+
+    /// Whether the login may use `scope`, the admin scope has to be granted explicitly.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        match &self.scopes {
+            Some(scopes) => scopes.iter().any(|s| s == scope),
+            None => scope != SCOPE_ADMIN,
+        }
+    }
+}
+
+pub const SCOPE_TUNNEL_HTTP: &str = "tunnel:http";
+pub const SCOPE_TUNNEL_TCP: &str = "tunnel:tcp";
+pub const SCOPE_ADMIN: &str = "admin";
+pub const SCOPES: [&str; 3] = [SCOPE_TUNNEL_HTTP, SCOPE_TUNNEL_TCP, SCOPE_ADMIN];
+
+/// What the client presented when logging in.
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    pub token: String,
+    /// Certificates of the client, when it connected with mTLS.
+    pub peer_certs: Option<Arc<Vec<Certificate>>>,
+}
+
+#[derive(Error, Debug)]
+pub enum AuthError {
+    /// The credentials are not valid for this backend.
+    #[error("{0}")]
+    Rejected(String),
+
+    /// The backend could not decide, e.g. the identity provider is down.
+    #[error("{0}")]
+    Unavailable(String),
+}
+
+impl From<OidcError> for AuthError {
+    fn from(e: OidcError) -> Self {
+        match e {
+            OidcError::Invalid(_) => AuthError::Rejected(e.to_string()),
+            OidcError::Unavailable(_) => AuthError::Unavailable(e.to_string()),
+        }
+    }
+}
+
+/// Resolves the credentials presented on login to an [`Identity`].
+#[tonic::async_trait]
+pub trait Authenticator: Debug + Send + Sync {
+    async fn authenticate(&self, creds: &Credentials) -> Result<Identity, AuthError>;
+}
+
+/// Tries each authenticator in order, the first one accepting the credentials wins.
+#[derive(Debug, Clone, Default)]
+pub struct Chain {
+    backends: Vec<Arc<dyn Authenticator>>,
+}
+
+impl Chain {
+    pub fn new() -> Self {
+        Chain::default()
+    }
+
+    pub fn with(mut self, backend: impl Authenticator + 'static) -> Self {
+        self.backends.push(Arc::new(backend));
+        self
+    }
+
+    /// Builds the chain listed in `core.auth_method`.
+    pub fn from_config(cfg: &Config) -> anyhow::Result<Self> {
+        let mut chain = Chain::new();
+        for method in cfg.core.auth_methods() {
+            chain = match method {
+                "token" => chain.with(StaticTokens::new(cfg.tokens.clone())?),
+                "cert" => chain.with(ClientCert),
+                "oidc" => chain.with(OidcVerifier::new(cfg.oidc.clone().context("missing [oidc] section")?)?),
+                "htpasswd" => chain.with(Htpasswd::load(&cfg.htpasswd.as_ref().context("missing [htpasswd] section")?.file)?),
+                "callout" => chain.with(Callout::new(cfg.callout.clone().context("missing [callout] section")?)?),
+                _ => bail!("unknown auth_method {}", method),
+            };
+        }
+        Ok(chain)
+    }
+}
+
+#[tonic::async_trait]
+impl Authenticator for Chain {
+    async fn authenticate(&self, creds: &Credentials) -> Result<Identity, AuthError> {
+        let mut result = Err(AuthError::Rejected("no authentication method configured".to_string()));
+        for backend in &self.backends {
+            match backend.authenticate(creds).await {
+                Ok(identity) => return Ok(identity),
+                // 有后端不可用时优先报告不可用，客户端可以稍后重试
+                Err(err @ AuthError::Unavailable(_)) => {
+                    warn!("authentication backend unavailable: {}", err);
+                    result = Err(err);
+                }
+                Err(err) => {
+                    debug!("authentication rejected: {}", err);
+                    if !matches!(result, Err(AuthError::Unavailable(_))) {
+                        result = Err(err);
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+/// The `[tokens]` table of the config, mapping usernames to tokens or their hashes.
+#[derive(Debug, Clone)]
+pub struct StaticTokens {
+    tokens: Arc<Vec<TokenRecord>>,
+}
+
+#[derive(Debug)]
+struct TokenRecord {
+    username: String,
+    stored: StoredToken,
+    expires_at: Option<u64>,
+    scopes: Option<Vec<String>>,
+    description: Option<String>,
+}
+
+impl TokenRecord {
+    fn new(username: String, entry: TokenEntry) -> anyhow::Result<Self> {
+        let cfg = match entry {
+            TokenEntry::Token(token) => TokenConfig { token, expires: None, description: None, scopes: None },
+            TokenEntry::Detailed(cfg) => cfg,
+        };
+        let (stored, plaintext) = StoredToken::parse(&cfg.token)?;
+        if plaintext {
+            warn!("token of user {} is stored in plaintext, replace it with the output of `rslocald token hash`", username);
+        }
+        let expires_at = cfg.expires.as_deref().map(token::parse_expiry).transpose()?;
+        if expires_at.is_some_and(|at| at <= get_current_timestamp()) {
+            info!("token of user {} has expired", username);
+        }
+        if let Some(scope) = cfg.scopes.iter().flatten().find(|s| !SCOPES.contains(&s.as_str())) {
+            bail!("unknown scope {}, expected one of {}", scope, SCOPES.join(", "));
+        }
+        Ok(TokenRecord { username, stored, expires_at, scopes: cfg.scopes, description: cfg.description })
+    }
+
+    fn identity(&self) -> Result<Identity, AuthError> {
+        if self.expires_at.is_some_and(|at| at <= get_current_timestamp()) {
+            return Err(AuthError::Rejected("token expired".to_string()));
+        }
+        let mut identity = Identity::new(&self.username);
+        identity.scopes = self.scopes.clone();
+        identity.expires_at = self.expires_at;
+        if let Some(description) = &self.description {
+            identity.attributes.insert("description".to_string(), description.clone());
+        }
+        Ok(identity)
+    }
+}
+
+impl StaticTokens {
+    pub fn new(tokens: HashMap<String, TokenEntry>) -> anyhow::Result<Self> {
+        let mut records = Vec::with_capacity(tokens.len());
+        for (username, entry) in tokens {
+            let record = TokenRecord::new(username.clone(), entry).with_context(|| format!("token of user {}", username))?;
+            records.push(record);
+        }
+        Ok(StaticTokens { tokens: Arc::new(records) })
+    }
+}
+
+#[tonic::async_trait]
+impl Authenticator for StaticTokens {
+    async fn authenticate(&self, creds: &Credentials) -> Result<Identity, AuthError> {
+        let rejected = || AuthError::Rejected("invalid token".to_string());
+        // 比较全部条目而不是找到即返回，耗时与匹配位置无关
+        let digest = token::sha256(&creds.token);
+        let mut matched = None;
+        for (i, record) in self.tokens.iter().enumerate().filter(|(_, r)| !r.stored.is_argon2()) {
+            if record.stored.verify(&creds.token, &digest) && matched.is_none() {
+                matched = Some(i);
+            }
+        }
+        if let Some(i) = matched {
+            return self.tokens[i].identity();
+        }
+        if !self.tokens.iter().any(|r| r.stored.is_argon2()) {
+            return Err(rejected());
+        }
+
+        // argon2计算较慢，避免阻塞运行时
+        let tokens = self.tokens.clone();
+        let token = creds.token.clone();
+        let matched = tokio::task::spawn_blocking(move || {
+            tokens.iter().position(|r| r.stored.is_argon2() && r.stored.verify(&token, &digest))
+        }).await.map_err(|e| AuthError::Unavailable(e.to_string()))?;
+        matched.map_or_else(|| Err(rejected()), |i| self.tokens[i].identity())
+    }
+}
+
+/// Uses the common name of the client certificate as the username, requires mTLS.
+#[derive(Debug, Clone)]
+pub struct ClientCert;
+
+#[tonic::async_trait]
+impl Authenticator for ClientCert {
+    async fn authenticate(&self, creds: &Credentials) -> Result<Identity, AuthError> {
+        let required = || AuthError::Rejected("client certificate required".to_string());
+        let cert = creds.peer_certs.as_ref().and_then(|certs| certs.first()).ok_or_else(required)?;
+        let (_, x509) = x509_parser::parse_x509_certificate(cert.get_ref())
+            .map_err(|_| AuthError::Rejected("invalid client certificate".to_string()))?;
+        let username = x509.subject().iter_common_name().next()
+            .and_then(|cn| cn.as_str().ok())
+            .ok_or_else(|| AuthError::Rejected("client certificate has no common name".to_string()))?;
+        Ok(Identity::new(username))
+    }
+}
+
+#[tonic::async_trait]
+impl Authenticator for OidcVerifier {
+    async fn authenticate(&self, creds: &Credentials) -> Result<Identity, AuthError> {
+        Ok(self.verify(&creds.token).await?)
+    }
+}
+
+/// Users of an htpasswd file, the client's token is `username:password`.
+#[derive(Debug, Clone)]
+pub struct Htpasswd {
+    users: HashMap<String, String>,
+}
+
+impl Htpasswd {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("failed to read htpasswd file {}", path))?;
+        let mut users = HashMap::new();
+        for line in content.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let (username, hash) = match line.split_once(':') {
+                Some(entry) => entry,
+                None => bail!("malformed line in {}: {}", path, line),
+            };
+            // 只支持bcrypt，MD5/SHA1等弱哈希直接忽略
+            if !hash.starts_with("$2") {
+                warn!("ignore htpasswd user {}: only bcrypt hashes are supported", username);
+                continue;
+            }
+            users.insert(username.to_string(), hash.to_string());
+        }
+        Ok(Htpasswd { users })
+    }
+}
+
+#[tonic::async_trait]
+impl Authenticator for Htpasswd {
+    async fn authenticate(&self, creds: &Credentials) -> Result<Identity, AuthError> {
+        let rejected = || AuthError::Rejected("invalid username or password".to_string());
+        let (username, password) = creds.token.split_once(':').ok_or_else(rejected)?;
+        let hash = self.users.get(username).ok_or_else(rejected)?.clone();
+
+        // bcrypt计算较慢，避免阻塞运行时
+        let password = password.to_string();
+        let valid = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+            .await
+            .map_err(|e| AuthError::Unavailable(e.to_string()))?
+            .unwrap_or(false);
+        if !valid {
+            return Err(rejected());
+        }
+        Ok(Identity::new(username))
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CalloutRequest<'a> {
+    token: &'a str,
+}
+
+/// Asks an external service to authenticate the token.
+///
+/// The token is POSTed as `{"token": "..."}`, the service answers 200 with an
+/// [`Identity`] as JSON, or 401/403 to reject it.
+#[derive(Debug, Clone)]
+pub struct Callout {
+    cfg: CalloutConfig,
+    http: reqwest::Client,
+}
+
+impl Callout {
+    pub fn new(cfg: CalloutConfig) -> anyhow::Result<Self> {
+        let http = reqwest::Client::builder().timeout(Duration::from_secs(cfg.timeout)).build()?;
+        Ok(Callout { cfg, http })
+    }
+}
+
+#[tonic::async_trait]
+impl Authenticator for Callout {
+    async fn authenticate(&self, creds: &Credentials) -> Result<Identity, AuthError> {
+        let mut req = self.http.post(&self.cfg.url).json(&CalloutRequest { token: &creds.token });
+        if let Some(authorization) = &self.cfg.authorization {
+            req = req.header(reqwest::header::AUTHORIZATION, authorization);
+        }
+
+        let unavailable = |e: reqwest::Error| AuthError::Unavailable(format!("{}: {}", self.cfg.url, e));
+        let resp = req.send().await.map_err(unavailable)?;
+        match resp.status() {
+            StatusCode::OK => {
+                let identity: Identity = resp.json().await.map_err(unavailable)?;
+                if identity.username.is_empty() {
+                    return Err(AuthError::Unavailable(format!("{}: empty username", self.cfg.url)));
+                }
+                Ok(identity)
+            }
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(AuthError::Rejected("rejected by auth service".to_string())),
+            status => Err(AuthError::Unavailable(format!("{}: unexpected status {}", self.cfg.url, status))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::{hash_token, HashAlgorithm};
+    use super::*;
+
+    const ALICE: &str = "rslocald_Pz3xLq8VwN5tKe2M";
+    const BOB: &str = "rslocald_Wq8ZkP3nVx7Lm2Rt";
+
+    fn creds(token: &str) -> Credentials {
+        Credentials { token: token.to_string(), peer_certs: None }
+    }
+
+    fn detailed(token: &str, expires: Option<&str>, scopes: Option<&[&str]>) -> TokenEntry {
+        TokenEntry::Detailed(TokenConfig {
+            token: token.to_string(),
+            expires: expires.map(String::from),
+            description: Some("test".to_string()),
+            scopes: scopes.map(|s| s.iter().map(|s| s.to_string()).collect()),
+        })
+    }
+
+    fn tokens(entries: Vec<(&str, TokenEntry)>) -> StaticTokens {
+        StaticTokens::new(entries.into_iter().map(|(u, e)| (u.to_string(), e)).collect()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn static_tokens_match_every_format() {
+        let tokens = tokens(vec![
+            ("alice", TokenEntry::Token(hash_token(ALICE, HashAlgorithm::Sha256).unwrap())),
+            ("bob", TokenEntry::Token(hash_token(BOB, HashAlgorithm::Argon2).unwrap())),
+            ("carol", TokenEntry::Token("rslocald_Cr7yHn2QmZ4xTb9W".to_string())),
+        ]);
+        assert_eq!(tokens.authenticate(&creds(ALICE)).await.unwrap().username, "alice");
+        assert_eq!(tokens.authenticate(&creds(BOB)).await.unwrap().username, "bob");
+        assert_eq!(tokens.authenticate(&creds("rslocald_Cr7yHn2QmZ4xTb9W")).await.unwrap().username, "carol");
+        assert!(matches!(tokens.authenticate(&creds("rslocald_Unkn0wnT0kenXyZ")).await, Err(AuthError::Rejected(_))));
+        assert!(matches!(tokens.authenticate(&creds("")).await, Err(AuthError::Rejected(_))));
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_rejected() {
+        let tokens = tokens(vec![
+            ("alice", detailed(ALICE, Some("2020-01-01"), None)),
+            ("bob", detailed(BOB, Some("2999-01-01T00:00:00Z"), None)),
+        ]);
+        assert!(matches!(tokens.authenticate(&creds(ALICE)).await, Err(AuthError::Rejected(_))));
+        let bob = tokens.authenticate(&creds(BOB)).await.unwrap();
+        assert_eq!(bob.expires_at, Some(token::parse_expiry("2999-01-01").unwrap()));
+        assert_eq!(bob.attributes.get("description").map(String::as_str), Some("test"));
+    }
+
+    #[tokio::test]
+    async fn scopes_are_parsed() {
+        let tokens = tokens(vec![
+            ("alice", detailed(ALICE, None, Some(&[SCOPE_TUNNEL_TCP, SCOPE_ADMIN]))),
+            ("bob", TokenEntry::Token(BOB.to_string())),
+        ]);
+        let alice = tokens.authenticate(&creds(ALICE)).await.unwrap();
+        assert!(alice.has_scope(SCOPE_TUNNEL_TCP) && alice.has_scope(SCOPE_ADMIN));
+        assert!(!alice.has_scope(SCOPE_TUNNEL_HTTP));
+        // 未指定时拥有除admin以外的全部权限
+        let bob = tokens.authenticate(&creds(BOB)).await.unwrap();
+        assert!(bob.has_scope(SCOPE_TUNNEL_HTTP) && bob.has_scope(SCOPE_TUNNEL_TCP));
+        assert!(!bob.has_scope(SCOPE_ADMIN));
+
+        let unknown = HashMap::from([("alice".to_string(), detailed(ALICE, None, Some(&["tunnel:udp"])))]);
+        assert!(StaticTokens::new(unknown).is_err());
+    }
+
+    #[test]
+    fn invalid_entries_are_rejected() {
+        for entry in ["short", "aaaaaaaaaaaaaaaaaaaa", "sha256:abcd", "$argon2id$nonsense"] {
+            let entries = HashMap::from([("alice".to_string(), TokenEntry::Token(entry.to_string()))]);
+            assert!(StaticTokens::new(entries).is_err(), "{}", entry);
+        }
+        let expiry = HashMap::from([("alice".to_string(), detailed(ALICE, Some("tomorrow"), None))]);
+        assert!(StaticTokens::new(expiry).is_err());
+    }
+
+    #[derive(Debug)]
+    struct Fixed(Result<&'static str, bool>);
+
+    #[tonic::async_trait]
+    impl Authenticator for Fixed {
+        async fn authenticate(&self, _: &Credentials) -> Result<Identity, AuthError> {
+            match self.0 {
+                Ok(username) => Ok(Identity::new(username)),
+                Err(true) => Err(AuthError::Unavailable("down".to_string())),
+                Err(false) => Err(AuthError::Rejected("rejected".to_string())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn chain_falls_through_to_the_first_match() {
+        let chain = Chain::new().with(Fixed(Err(false))).with(Fixed(Err(true))).with(Fixed(Ok("alice"))).with(Fixed(Ok("bob")));
+        assert_eq!(chain.authenticate(&creds(ALICE)).await.unwrap().username, "alice");
+
+        // 有后端不可用时报告不可用，不论顺序
+        let chain = Chain::new().with(Fixed(Err(true))).with(Fixed(Err(false)));
+        assert!(matches!(chain.authenticate(&creds(ALICE)).await, Err(AuthError::Unavailable(_))));
+        let chain = Chain::new().with(Fixed(Err(false))).with(Fixed(Err(true)));
+        assert!(matches!(chain.authenticate(&creds(ALICE)).await, Err(AuthError::Unavailable(_))));
+
+        let chain = Chain::new().with(Fixed(Err(false))).with(Fixed(Err(false)));
+        assert!(matches!(chain.authenticate(&creds(ALICE)).await, Err(AuthError::Rejected(_))));
+        assert!(matches!(Chain::new().authenticate(&creds(ALICE)).await, Err(AuthError::Rejected(_))));
+    }
+
+    #[tokio::test]
+    async fn chain_tries_static_tokens_before_the_next_backend() {
+        let chain = Chain::new().with(tokens(vec![("alice", TokenEntry::Token(ALICE.to_string()))])).with(Fixed(Ok("fallback")));
+        assert_eq!(chain.authenticate(&creds(ALICE)).await.unwrap().username, "alice");
+        assert_eq!(chain.authenticate(&creds(BOB)).await.unwrap().username, "fallback");
+    }
+}
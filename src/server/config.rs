@@ -2,31 +2,257 @@ use std::collections::HashMap;
 
 
 use config::{ConfigError, Environment, File};
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 use log::info;
+use crate::server::proxy_protocol::Cidr;
 
-#[derive(Debug, Clone, Deserialize)]
+const MIN_SESSION_SECRET_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 #[allow(unused)]
 pub struct Core {
     pub debug: bool,
     pub bind_addr: String,
     pub auth_method: String,
     pub allow_ports: String,
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    #[serde(default)]
+    pub trusted_proxies: Vec<Cidr>,
+    /// Seconds a visitor connection may go without traffic before it is closed, 0 disables.
+    #[serde(default)]
+    pub idle_timeout: u64,
+    /// Seconds between heartbeats to clients, 0 disables them.
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+    /// Seconds a client may stay silent before its tunnel is torn down, at least `heartbeat_interval`.
+    #[serde(default = "default_heartbeat_timeout")]
+    pub heartbeat_timeout: u64,
+    /// Seconds a session lasts after login before the client must log in again, 0 disables.
+    #[serde(default)]
+    pub session_ttl: u64,
+    /// Seconds a session without tunnels may stay unused before it expires, 0 disables.
+    #[serde(default = "default_session_idle_timeout")]
+    pub session_idle_timeout: u64,
+    /// Login attempts per minute accepted from one address, 0 disables.
+    #[serde(default = "default_login_rate_limit")]
+    pub login_rate_limit: u32,
+    /// Login attempts per minute accepted from all addresses together, 0 disables.
+    #[serde(default = "default_login_global_rate_limit")]
+    pub login_global_rate_limit: u32,
+    /// Failed logins from one address before it is locked out, 0 disables lockouts.
+    #[serde(default = "default_login_max_failures")]
+    pub login_max_failures: u32,
+    /// Seconds of the first lockout, doubled with every further failed login.
+    #[serde(default = "default_login_lockout")]
+    pub login_lockout: u64,
+    /// Upper bound of a lockout in seconds, failures older than this are forgotten.
+    #[serde(default = "default_login_max_lockout")]
+    pub login_max_lockout: u64,
+}
+
+impl Core {
+    /// The authentication backends to try in order, `auth_method` is a comma separated list.
+    pub fn auth_methods(&self) -> Vec<&str> {
+        self.auth_method.split(',').map(str::trim).filter(|m| !m.is_empty()).collect()
+    }
+}
+
+fn default_heartbeat_interval() -> u64 {
+    15
+}
+
+fn default_heartbeat_timeout() -> u64 {
+    45
+}
+
+fn default_session_idle_timeout() -> u64 {
+    3600
+}
+
+fn default_login_rate_limit() -> u32 {
+    30
+}
+
+fn default_login_global_rate_limit() -> u32 {
+    600
 }
 
-#[derive(Debug, Clone, Deserialize)]
+fn default_login_max_failures() -> u32 {
+    5
+}
+
+fn default_login_lockout() -> u64 {
+    60
+}
+
+fn default_login_max_lockout() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 #[allow(unused)]
 pub struct HTTPConfig {
     pub bind_addr: String,
     pub default_domain: String,
+    /// HTML page returned with 502 when the local service is unavailable,
+    /// `{reason}` is replaced by the error reported by the client.
+    #[serde(default)]
+    pub unavailable_page: Option<String>,
+    /// Seconds to wait for the local service's response headers before returning 504.
+    #[serde(default = "default_response_timeout")]
+    pub response_timeout: u64,
+}
+
+fn default_response_timeout() -> u64 {
+    60
+}
+
+/// TLS settings of the gRPC server, files are PEM encoded.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TLSConfig {
+    pub cert: String,
+    pub key: String,
+    /// When set, clients must present a certificate signed by this CA.
+    #[serde(default)]
+    pub client_ca: Option<String>,
+}
+
+/// Identity provider used when `auth_method = "oidc"`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct OIDCConfig {
+    pub issuer: String,
+    pub audience: String,
+    /// Claim used as the username.
+    #[serde(default = "default_username_claim")]
+    pub username_claim: String,
+    /// Claim holding the user's groups, a string or an array of strings.
+    #[serde(default = "default_groups_claim")]
+    pub groups_claim: String,
+    /// Fetch signing keys from this url instead of the issuer's discovery document.
+    #[serde(default)]
+    pub jwks_uri: Option<String>,
+    /// Load signing keys from a local JWKS file instead of fetching them.
+    #[serde(default)]
+    pub jwks_file: Option<String>,
+}
+
+fn default_username_claim() -> String {
+    "sub".to_string()
+}
+
+fn default_groups_claim() -> String {
+    "groups".to_string()
+}
+
+/// Users authenticated with `username:password` tokens when `auth_method` includes "htpasswd".
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct HtpasswdConfig {
+    /// An htpasswd file with bcrypt hashes (`htpasswd -B`).
+    pub file: String,
+}
+
+/// External service asked to authenticate tokens when `auth_method` includes "callout".
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CalloutConfig {
+    pub url: String,
+    /// Value of the Authorization header sent to the service.
+    #[serde(default)]
+    pub authorization: Option<String>,
+    /// Seconds to wait for the service to answer.
+    #[serde(default = "default_callout_timeout")]
+    pub timeout: u64,
+}
+
+fn default_callout_timeout() -> u64 {
+    5
+}
+
+/// The management API, served on its own listener.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AdminConfig {
+    pub bind_addr: String,
+    /// Members of these groups are administrators, besides tokens with the `admin` scope.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// JSON file keeping the tokens and reservations managed through the API,
+    /// they are lost on restart without it.
+    #[serde(default)]
+    pub state_file: Option<String>,
+}
+
+/// Key signing stateless session tokens, shared by all server instances.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SessionKey {
+    pub id: String,
+    pub secret: String,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// An entry of the `[tokens]` table, the token (or its hash) or a table restricting it.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum TokenEntry {
+    Token(String),
+    Detailed(TokenConfig),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TokenConfig {
+    pub token: String,
+    /// UTC time the token expires at, e.g. `2024-07-01T12:00:00Z` or `2024-07-01`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Scopes granted to the token, all but `admin` when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<String>>,
+}
+
+/// Restricts the tunnels of the users and groups it names, the first matching policy applies.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct PolicyConfig {
+    /// Usernames the policy applies to, `*` matches every user.
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Protocols the users may listen with, empty allows all.
+    #[serde(default)]
+    pub protocols: Vec<String>,
+    /// Allowed subdomain patterns, `*` matches any characters and `{username}` the name of the user.
+    #[serde(default)]
+    pub subdomains: Vec<String>,
+    /// Port range like `20000-20100` used instead of `core.allow_ports`.
+    #[serde(default)]
+    pub ports: Option<String>,
+    /// Simultaneous tunnels per user, 0 is unlimited.
+    #[serde(default)]
+    pub max_tunnels: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 #[allow(unused)]
 pub struct Config {
     pub core: Core,
     pub http: HTTPConfig,
-    pub tokens: HashMap<String, String>,
+    #[serde(default)]
+    pub tls: Option<TLSConfig>,
+    #[serde(default)]
+    pub oidc: Option<OIDCConfig>,
+    #[serde(default)]
+    pub htpasswd: Option<HtpasswdConfig>,
+    #[serde(default)]
+    pub callout: Option<CalloutConfig>,
+    /// When set, sessions are signed tokens instead of in-memory ids. The first key
+    /// signs new sessions, the others are only accepted to allow key rotation.
+    #[serde(default)]
+    pub session_keys: Vec<SessionKey>,
+    #[serde(default)]
+    pub policies: Vec<PolicyConfig>,
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+    pub tokens: HashMap<String, TokenEntry>,
 }
 
 impl Config {
@@ -45,6 +271,45 @@ impl Config {
         info!("core_bind_addr: {:?}", s.get::<String>("core.bind_addr"));
 
         // You can deserialize (and thus freeze) the entire configuration as
-        s.try_deserialize()
+        let cfg: Config = s.try_deserialize()?;
+        if cfg.core.proxy_protocol && cfg.core.trusted_proxies.is_empty() {
+            return Err(ConfigError::Message("proxy_protocol requires trusted_proxies".to_string()));
+        }
+        // 超时短于心跳间隔时，客户端会在两次心跳之间被误判为失联
+        if cfg.core.heartbeat_interval > 0 && cfg.core.heartbeat_timeout < cfg.core.heartbeat_interval {
+            return Err(ConfigError::Message("heartbeat_timeout must be at least heartbeat_interval".to_string()));
+        }
+        if cfg.core.heartbeat_timeout > u32::MAX as u64 {
+            return Err(ConfigError::Message("heartbeat_timeout is too large".to_string()));
+        }
+        // 锁定时长上限小于首次锁定时长时，锁定永远不会生效
+        if cfg.core.login_max_failures > 0 && (cfg.core.login_lockout == 0 || cfg.core.login_max_lockout < cfg.core.login_lockout) {
+            return Err(ConfigError::Message("login lockouts require 0 < login_lockout <= login_max_lockout".to_string()));
+        }
+        for method in cfg.core.auth_methods() {
+            let configured = match method {
+                "token" | "cert" => true,
+                "oidc" => cfg.oidc.is_some(),
+                "htpasswd" => cfg.htpasswd.is_some(),
+                "callout" => cfg.callout.is_some(),
+                _ => return Err(ConfigError::Message(format!("unknown auth_method {}", method))),
+            };
+            if !configured {
+                return Err(ConfigError::Message(format!("auth_method {} requires the [{}] section", method, method)));
+            }
+        }
+        let mut ids = std::collections::HashSet::new();
+        for key in &cfg.session_keys {
+            if key.id.is_empty() || !ids.insert(key.id.as_str()) {
+                return Err(ConfigError::Message(format!("session key ids must be unique and not empty: {:?}", key.id)));
+            }
+            if key.secret.len() < MIN_SESSION_SECRET_LEN {
+                return Err(ConfigError::Message(format!("session key {} must be at least {} bytes", key.id, MIN_SESSION_SECRET_LEN)));
+            }
+        }
+        for policy in &cfg.policies {
+            policy.validate().map_err(ConfigError::Message)?;
+        }
+        Ok(cfg)
     }
 }
\ No newline at end of file
@@ -1,125 +1,267 @@
 use std::pin::Pin;
-use std::collections::HashMap;
 use std::sync::{Arc};
-use std::time::Duration;
-use dashmap::DashSet;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+use dashmap::{DashMap, DashSet};
+use jsonwebtoken::get_current_timestamp;
 
 use futures::{Stream, StreamExt};
-use log::{debug, info};
-use tokio::sync::{mpsc, Mutex, MutexGuard};
-use tokio::sync::mpsc::Sender;
-use tokio::time::sleep;
+use log::{debug, info, warn};
+use tokio::sync::{mpsc, oneshot, watch, Mutex, MutexGuard};
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::time::interval;
 use tokio_stream::{wrappers::ReceiverStream};
+use tokio_util::sync::CancellationToken;
 use tonic::{Request, Response, Status, Streaming};
 use tonic::service::Interceptor;
-use crate::{random_string};
-use crate::server::api::{LoginBody, LoginReply, TransferBody, TransferReply, ListenNotification, Protocol, ListenParam, TStatus};
+use crate::{protocol, random_string};
+use crate::mux::{self, RecvWindow, Window};
+use crate::server::api::{LoginBody, LoginReply, TransferBody, TransferReply, ListenNotification, Protocol, ListenParam, TStatus, ReadyEvent, ErrorEvent, ShutdownEvent, PingEvent, TunnelInfo};
+use crate::server::api::listen_notification::Event;
 use crate::server::api::tunnel_server::{Tunnel};
-use crate::server::{Config, grpc, Payload, XData, Connection};
+use crate::server::{Active, AdminState, AuthError, Credentials, find_policy, Identity, is_dns_label, LoginLimiter, parse_port_range, SCOPE_TUNNEL_HTTP, SCOPE_TUNNEL_TCP, Session, SessionEnd, SessionStore, SessionTunnel, Shared, too_many_attempts, UserTunnel, UserTunnels, grpc, Payload, XData, Connection, METRICS, Runtime};
 use crate::server::api::user_server::User;
+use crate::server::config::PolicyConfig;
 
 pub mod api {
     tonic::include_proto!("api");
 }
 
-const AUTH_METHOD_TOKEN: &str = "token";
-const AUTH_METHOD_OIDC: &str = "oidc";
-
 #[derive(Debug, Clone)]
 pub struct RSLUser {
-    cfg: Config,
+    runtime: Shared<Runtime>,
+
+    sessions: Arc<SessionStore>,
 
-    sessions: Arc<parking_lot::Mutex<HashMap<String, String>>>,
+    limiter: Arc<LoginLimiter>,
 }
 
 impl Interceptor for RSLUser {
-    fn call(&mut self, req: tonic::Request<()>) -> Result<tonic::Request<()>, Status> {
-        let ss = self.sessions.clone();
-
-        match req.metadata().get("authorization") {
-            Some(session) => {
-                if let Some(_) = ss.lock().get(session.to_str().unwrap()) {
-                    return Ok(req);
-                }
-                Err(Status::unauthenticated("invalid session"))
-            }
+    fn call(&mut self, mut req: tonic::Request<()>) -> Result<tonic::Request<()>, Status> {
+        let session = match req.metadata().get("authorization").map(|s| s.to_str()) {
+            Some(Ok(session_id)) => self.sessions.get(session_id).ok_or_else(|| Status::unauthenticated("invalid session"))?,
+            _ => return Err(Status::unauthenticated("No valid auth token")),
+        };
 
-            _ => Err(Status::unauthenticated("No valid auth token")),
-        }
+        // 交给后续的RPC，隧道随会话结束而关闭
+        req.extensions_mut().insert(session);
+        Ok(req)
     }
 }
 
 impl RSLUser {
-    pub fn new(cfg: Config) -> Self {
-        RSLUser { cfg, sessions: Arc::new(Default::default()) }
-    }
-    fn token2username(&self, token: String) -> Result<String, Status> {
-        let cfg = self.cfg.clone();
-        if cfg.core.auth_method == *AUTH_METHOD_OIDC {
-            // todo implement oidc auth
-            return Err(Status::invalid_argument("oidc not implement"));
-        }
-
-        for (k, v) in cfg.tokens {
-            if v == token {
-                return Ok(k);
-            }
-        };
-
-        Err(Status::invalid_argument("invalid token"))
+    pub fn new(runtime: Shared<Runtime>, sessions: Arc<SessionStore>, limiter: Arc<LoginLimiter>) -> Self {
+        RSLUser { runtime, sessions, limiter }
     }
 }
 
 #[tonic::async_trait]
 impl User for RSLUser {
     async fn login(&self, request: Request<LoginBody>) -> Result<Response<LoginReply>, Status> {
-        let param = request.into_inner();
-        let token = param.token;
+        let addr = request.remote_addr();
+        let ip = addr.map(|addr| addr.ip());
+        self.limiter.check(ip).map_err(too_many_attempts)?;
 
-        // 验证token是否正确并获取用户名
-        let username = self.token2username(token)?;
+        let peer_certs = request.peer_certs();
+        let param = request.into_inner();
+        protocol::check_version("client", param.protocol_version).map_err(Status::failed_precondition)?;
+
+        // 依次尝试配置的认证方式并获取用户身份
+        let creds = Credentials { token: param.token, peer_certs };
+        let auth = self.runtime.load().auth.clone();
+        let identity = match auth.authenticate(&creds).await {
+            Ok(identity) => identity,
+            Err(AuthError::Rejected(msg)) => {
+                warn!("login from {} failed: {}", addr.map_or("unknown address".to_string(), |a| a.to_string()), msg);
+                self.limiter.failed(ip);
+                return Err(Status::unauthenticated(msg));
+            }
+            Err(AuthError::Unavailable(msg)) => return Err(Status::unavailable(msg)),
+        };
+        self.limiter.succeeded(ip);
+        let username = identity.username.clone();
+        if identity.scopes.as_ref().is_some_and(Vec::is_empty) {
+            return Err(Status::permission_denied(format!("token of user {} has no scopes", username)));
+        }
         info!("user {} logged in", username);
 
-        let session_id: String = random_string(128);
-        debug!("user {} session: {:?}", username, session_id);
-
         // 存储Session
-        let mut sessions = self.sessions.lock();
-        sessions.insert(session_id.clone(), username.clone());
+        let session_id = self.sessions.create(identity);
+        debug!("user {} session: {:?}", username, session_id);
         Ok(Response::new(LoginReply {
             session_id,
             username,
+            protocol_version: protocol::PROTOCOL_VERSION,
+            capabilities: protocol::negotiate(&param.capabilities),
         }))
     }
-}
 
-const ACTION_READY: &str = "ready";
-const ACTION_COMING: &str = "coming";
+    async fn logout(&self, request: Request<()>) -> Result<Response<()>, Status> {
+        let session_id = request.metadata().get("authorization")
+            .and_then(|s| s.to_str().ok())
+            .ok_or_else(|| Status::unauthenticated("No valid auth token"))?;
+        if !self.sessions.remove(session_id) {
+            return Err(Status::unauthenticated("invalid session"));
+        }
+        Ok(Response::new(()))
+    }
+}
 
 #[derive(Debug)]
 pub struct RSLServer {
-    cfg: Config,
+    runtime: Shared<Runtime>,
+    state: Shared<AdminState>,
     tx_tcp: Sender<Payload>,
     tx_http: Sender<Payload>,
+    shutdown: watch::Receiver<String>,
 
-    conns: Arc<Mutex<HashMap<String, Connection>>>,
+    tunnels: TunnelRegistry,
     entrypoints: Arc<Mutex<DashSet<String>>>,
+    user_tunnels: UserTunnels,
+}
+
+/// The tunnels being served by id, shared with the admin API.
+#[derive(Debug, Clone, Default)]
+pub struct TunnelRegistry(Arc<DashMap<String, TunnelHandle>>);
+
+impl TunnelRegistry {
+    /// The tunnels with their current traffic, oldest first.
+    pub fn list(&self) -> Vec<TunnelInfo> {
+        let mut tunnels: Vec<TunnelInfo> = self.0.iter().map(|t| TunnelInfo {
+            connections: t.streams.len() as u32,
+            connections_total: t.stats.connections.load(Ordering::Relaxed),
+            bytes_in: t.stats.bytes_in.load(Ordering::Relaxed),
+            bytes_out: t.stats.bytes_out.load(Ordering::Relaxed),
+            ..t.info.clone()
+        }).collect();
+        tunnels.sort_by_key(|t| t.created_at);
+        tunnels
+    }
+
+    /// Closes the tunnel with the id or entrypoint `id`, the client gets `reason` as an error
+    /// and does not reconnect. Returns false if there is no such tunnel.
+    pub async fn close(&self, id: &str, reason: &str) -> bool {
+        let found = self.0.iter()
+            .find(|t| t.key() == id || t.info.entrypoint == id)
+            .map(|t| (t.notify.clone(), t.closed.clone()));
+        let (notify, closed) = match found {
+            Some(found) => found,
+            None => return false,
+        };
+        let message = reason.to_string();
+        let _ = notify.send(Ok(ListenNotification { event: Some(Event::Error(ErrorEvent { message })) })).await;
+        closed.cancel();
+        true
+    }
+}
+
+#[cfg(test)]
+impl TunnelRegistry {
+    /// Registers a tunnel no client serves, it is not counted in [`METRICS`].
+    pub(crate) fn insert_idle(&self, info: TunnelInfo) {
+        let (notify, _) = mpsc::channel(1);
+        self.0.insert(info.id.clone(), TunnelHandle {
+            info,
+            with_visitor: false,
+            conns: Default::default(),
+            streams: Default::default(),
+            heartbeat: Activity::new(),
+            stats: Default::default(),
+            notify,
+            closed: CancellationToken::new(),
+            _session: None,
+            _user: None,
+            _active: Box::leak(Box::<crate::server::Metrics>::default()).tunnel(),
+        });
+    }
+}
+
+/// A registered tunnel, waiting for the client to attach its `Transfer` stream.
+#[derive(Debug)]
+struct TunnelHandle {
+    info: TunnelInfo,
+    with_visitor: bool,
+    conns: parking_lot::Mutex<Option<Receiver<Connection>>>,
+    streams: Arc<DashMap<String, MuxStream>>,
+    heartbeat: Activity,
+    stats: Arc<TunnelStats>,
+    notify: ListenSender,
+    closed: CancellationToken,
+    _session: Option<SessionTunnel>,
+    _user: Option<UserTunnel>,
+    _active: Active,
+}
+
+/// Traffic of a tunnel since it was opened.
+#[derive(Debug, Default)]
+struct TunnelStats {
+    connections: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+/// Server side state of one connection multiplexed over a `Transfer` stream.
+#[derive(Debug)]
+struct MuxStream {
+    inbound: UnboundedSender<TransferBody>,
+    // 客户端必须遵守窗口，超出额度的连接会被重置，所以inbound中最多积压一个窗口的数据
+    recv_window: RecvWindow,
+    window: Window,
+    closed_halves: AtomicU8,
+    activity: Activity,
+    _active: Active,
+}
+
+/// When a connection last carried a frame in either direction.
+#[derive(Debug, Clone)]
+struct Activity {
+    since: Instant,
+    last: Arc<AtomicU64>,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity { since: Instant::now(), last: Default::default() }
+    }
+
+    fn touch(&self) {
+        self.last.store(self.since.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        self.since.elapsed().saturating_sub(Duration::from_millis(self.last.load(Ordering::Relaxed)))
+    }
 }
 
 impl RSLServer {
-    pub fn new(cfg: Config, tx_tcp: Sender<Payload>, tx_http: Sender<Payload>) -> Self {
-        Self { cfg, tx_tcp, tx_http, conns: Default::default(), entrypoints: Default::default() }
+    /// `shutdown` carries the reason announced to every client once the server stops,
+    /// The config in `runtime` and the reservations in `state` are read for each new tunnel so that changes apply to it.
+    pub fn new(runtime: Shared<Runtime>, state: Shared<AdminState>, tunnels: TunnelRegistry, tx_tcp: Sender<Payload>, tx_http: Sender<Payload>, shutdown: watch::Receiver<String>) -> Self {
+        Self { runtime, state, tx_tcp, tx_http, shutdown, tunnels, entrypoints: Default::default(), user_tunnels: Default::default() }
     }
 
-    fn build_http_host(&self, oep_set: &MutexGuard<DashSet<String>>, lp: ListenParam) -> Result<String, Status> {
+    #[allow(clippy::result_large_err)]
+    fn build_http_host(&self, oep_set: &MutexGuard<DashSet<String>>, lp: ListenParam, policy: Option<&PolicyConfig>, username: &str, state: &AdminState) -> Result<String, Status> {
         let mut subdomain = lp.subdomain;
         if subdomain.is_empty() {
-            subdomain = random_string(8); // 如果没有指定子域名则随机生成一个
+            // 如果没有指定子域名则随机生成一个，受限的用户按策略的格式生成
+            subdomain = policy.and_then(|p| p.random_subdomain(username)).unwrap_or_else(|| random_string(8));
             // fixme: 又很小几率生成出来的正好已经在使用了，这时会报错。
             // fixme: 随机生成时应该保证生成的域名可用，不应该报错
         }
+        if !is_dns_label(&subdomain) {
+            return Err(Status::invalid_argument(format!("subdomain {} is not a valid DNS label", subdomain)));
+        }
+        // 预留给该用户的子域名不受策略限制
+        match state.subdomain_owner(&subdomain.to_lowercase()) {
+            Some(owner) if owner == username => {}
+            Some(_) => return Err(Status::permission_denied(format!("subdomain {} is reserved", subdomain))),
+            None => if let Some(policy) = policy {
+                policy.check_subdomain(&subdomain, username)?;
+            },
+        }
 
-        let key = format!("http://{}.{}", subdomain, self.cfg.http.default_domain).to_lowercase();
+        let key = format!("http://{}.{}", subdomain, self.runtime.load().cfg.http.default_domain).to_lowercase();
         if oep_set.contains(key.as_str()) {
             return Err(Status::already_exists("subdomain already exist"));
         }
@@ -127,11 +269,22 @@ impl RSLServer {
         Ok(key)
     }
 
-    fn build_tcp_addr(&self, oep_set: &MutexGuard<DashSet<String>>) -> Result<String, Status> {
-        let (min_str, max_str) = self.cfg.core.allow_ports.split_once('-').unwrap();
-        let min: u16 = min_str.parse().unwrap();
-        let max: u16 = max_str.parse().unwrap();
-        for port in min..max {
+    #[allow(clippy::result_large_err)]
+    fn build_tcp_addr(&self, oep_set: &MutexGuard<DashSet<String>>, policy: Option<&PolicyConfig>, username: &str, state: &AdminState) -> Result<String, Status> {
+        // 优先使用为该用户预留的端口，其他用户则跳过这些端口
+        for port in state.ports_of(username) {
+            let oep = format!("tcp://0.0.0.0:{}", port);
+            if !oep_set.contains(oep.as_str()) {
+                return Ok(oep);
+            }
+        }
+
+        let (min, max) = match policy.and_then(PolicyConfig::port_range) {
+            Some(range) => range,
+            None => parse_port_range(&self.runtime.load().cfg.core.allow_ports)
+                .ok_or_else(|| Status::internal("invalid allow_ports in server config"))?,
+        };
+        for port in (min..=max).filter(|port| state.port_owner(*port).is_none()) {
             let oep = format!("tcp://0.0.0.0:{}", port);
             if !oep_set.contains(oep.as_str()) {
                 return Ok(oep);
@@ -142,19 +295,39 @@ impl RSLServer {
         Err(Status::internal("none valid tcp port"))
     }
 
-    async fn build_entrypoint(&self, lp: ListenParam) -> Result<String, Status> {
-        let oep_set = self.entrypoints.lock().await;
-        let oep_result = match Protocol::from_i32(lp.protocol).unwrap() {
-            Protocol::Http => self.build_http_host(&oep_set, lp),
-            Protocol::Tcp => self.build_tcp_addr(&oep_set)
+    /// Reserves an entrypoint within the policy of the user, the returned guard counts the tunnel.
+    async fn build_entrypoint(&self, protocol: Protocol, lp: ListenParam, identity: Option<&Identity>) -> Result<(String, Option<UserTunnel>), Status> {
+        let cfg = self.runtime.load().cfg.clone();
+        let policy = identity.and_then(|identity| find_policy(&cfg.policies, identity));
+        let username = identity.map(|identity| identity.username.as_str()).unwrap_or_default();
+        let scope = match protocol {
+            Protocol::Http => SCOPE_TUNNEL_HTTP,
+            Protocol::Tcp => SCOPE_TUNNEL_TCP,
         };
+        if identity.is_some_and(|identity| !identity.has_scope(scope)) {
+            return Err(Status::permission_denied(format!("token of user {} lacks the {} scope", username, scope)));
+        }
 
-        if let Ok(key) = oep_result {
-            oep_set.insert(key.clone());
-            return Ok(key);
+        let mut user_tunnel = None;
+        if let Some(policy) = policy {
+            policy.check_protocol(protocol, username)?;
+            if policy.max_tunnels > 0 {
+                user_tunnel = Some(self.user_tunnels.acquire(username, policy.max_tunnels).ok_or_else(|| {
+                    Status::resource_exhausted(format!("user {} already has {} tunnels", username, policy.max_tunnels))
+                })?);
+            }
+        }
+
+        let state = self.state.load();
+        let oep_set = self.entrypoints.lock().await;
+        let oep_result = match protocol {
+            Protocol::Http => self.build_http_host(&oep_set, lp, policy, username, &state),
+            Protocol::Tcp => self.build_tcp_addr(&oep_set, policy, username, &state)
         };
 
-        oep_result
+        let key = oep_result?;
+        oep_set.insert(key.clone());
+        Ok((key, user_tunnel))
     }
 
     fn select_protocol_tx(&self, protocol: Protocol) -> Sender<Payload> {
@@ -171,108 +344,627 @@ impl Tunnel for RSLServer {
     type ListenStream = Pin<Box<dyn Stream<Item=Result<grpc::api::ListenNotification, Status>> + Send>>;
 
     async fn listen(&self, req: tonic::Request<grpc::api::ListenParam>) -> Result<Response<Self::ListenStream>, Status> {
-        info!("client connected from: {:?}", req.remote_addr());
+        let remote_addr = req.remote_addr();
+        info!("client connected from: {:?}", remote_addr);
+        let session = req.extensions().get::<Session>().cloned();
         let lp = req.into_inner();
-        let event_tx = self.select_protocol_tx(Protocol::from_i32(lp.protocol).unwrap());
+        protocol::check_version("client", lp.protocol_version).map_err(Status::failed_precondition)?;
+        let mut capabilities = protocol::negotiate(&lp.capabilities);
+        let cfg = self.runtime.load().cfg.clone();
+        if cfg.core.heartbeat_interval == 0 {
+            capabilities.retain(|c| c != protocol::CAP_HEARTBEAT);
+        }
+        let with_visitor = capabilities.iter().any(|c| c == protocol::CAP_VISITOR_METADATA);
+        let with_heartbeat = capabilities.iter().any(|c| c == protocol::CAP_HEARTBEAT);
+        let protocol = Protocol::from_i32(lp.protocol).ok_or_else(|| Status::invalid_argument("unknown protocol"))?;
+        let event_tx = self.select_protocol_tx(protocol);
 
         // 创建一个外部访问端点
-        let entrypoint = self.build_entrypoint(lp.clone()).await?;
+        let identity = session.as_ref().map(Session::identity);
+        let (entrypoint, user_tunnel) = self.build_entrypoint(protocol, lp.clone(), identity).await?;
         info!("entrypoint: {} registered", entrypoint);
+
+        // 登记隧道，等待客户端建立Transfer流
+        let tunnel_id = random_string(32);
+        let (otx, orx) = mpsc::channel(128);
         let (tx, rx) = mpsc::channel(128);
-        tx.send(Ok(ListenNotification { action: ACTION_READY.to_string(), message: entrypoint.clone() })).await.unwrap();
+        let heartbeat = Activity::new();
+        // 客户端不再回应心跳或隧道被关闭时结束控制流，随后按断开处理
+        let closed = CancellationToken::new();
+        let info = TunnelInfo {
+            id: tunnel_id.clone(),
+            entrypoint: entrypoint.clone(),
+            protocol: protocol as i32,
+            username: identity.map(|identity| identity.username.clone()).unwrap_or_default(),
+            session_id: session.as_ref().map(|session| session.id().to_string()).unwrap_or_default(),
+            remote_addr: remote_addr.map(|addr| addr.to_string()).unwrap_or_default(),
+            created_at: get_current_timestamp(),
+            ..Default::default()
+        };
+        self.tunnels.0.insert(tunnel_id.clone(), TunnelHandle {
+            info,
+            with_visitor,
+            conns: parking_lot::Mutex::new(Some(orx)),
+            streams: Default::default(),
+            heartbeat: heartbeat.clone(),
+            stats: Default::default(),
+            notify: tx.clone(),
+            closed: closed.clone(),
+            _session: session.as_ref().map(Session::tunnel),
+            _user: user_tunnel,
+            _active: METRICS.tunnel(),
+        });
+
+        // 入口开始监听后才通知客户端就绪，端口被占用等失败直接返回
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let opened = match event_tx.send(Payload { tx: otx, entrypoint: entrypoint.clone(), ready: Some(ready_tx) }).await {
+            Ok(()) => ready_rx.await.unwrap_or_else(|_| Err("entrypoint service is not running".to_string())),
+            Err(_) => Err("entrypoint service is not running".to_string()),
+        };
+        if let Err(e) = opened {
+            self.tunnels.0.remove(&tunnel_id);
+            self.entrypoints.lock().await.remove(entrypoint.as_str());
+            warn!("entrypoint {} failed to open: {}", entrypoint, e);
+            return Err(Status::unavailable(e));
+        }
 
-        // 监听客户端断开
+        let (heartbeat_interval, heartbeat_timeout) = if with_heartbeat {
+            (cfg.core.heartbeat_interval, cfg.core.heartbeat_timeout)
+        } else {
+            (0, 0)
+        };
+        let ready = ReadyEvent {
+            entrypoint: entrypoint.clone(),
+            protocol_version: protocol::PROTOCOL_VERSION,
+            capabilities,
+            tunnel_id: tunnel_id.clone(),
+            heartbeat_interval: heartbeat_interval as u32,
+            heartbeat_timeout: heartbeat_timeout as u32,
+        };
+        let _ = tx.send(Ok(ListenNotification { event: Some(Event::Ready(ready)) })).await;
+
+        // 客户端断开时立即注销入口并释放隧道上的连接
         let txc = tx.clone();
         let etx = event_tx.clone();
         let epc = entrypoint.clone();
         let eps = self.entrypoints.clone();
+        let tunnels = self.tunnels.clone();
         tokio::spawn(async move {
-            loop {
-                if txc.is_closed() {
-                    let (tx, _) = mpsc::channel(128);
-                    etx.send(Payload { tx, entrypoint: epc.clone() }).await.unwrap();
-                    eps.lock().await.remove(epc.as_str());
-                    info!("entrypoint {} unregistered", epc);
-                    return;
-                }
-                sleep(Duration::from_secs(1)).await;
+            txc.closed().await;
+            let (tx, _) = mpsc::channel(128);
+            let _ = etx.send(Payload { tx, entrypoint: epc.clone(), ready: None }).await;
+            eps.lock().await.remove(epc.as_str());
+            if let Some((_, t)) = tunnels.0.remove(&tunnel_id) {
+                close_streams(&t.streams);
             }
+            info!("entrypoint {} unregistered", epc);
         });
 
-        // 通知有新客户端连入
-        let (otx, mut orx) = mpsc::channel(128);
-        event_tx.send(Payload { tx: otx, entrypoint }).await.unwrap();
-        debug!("send done");
+        if with_heartbeat {
+            let interval = Duration::from_secs(heartbeat_interval);
+            let timeout = Duration::from_secs(heartbeat_timeout);
+            tokio::spawn(ping_client(tx.clone(), heartbeat, interval, timeout, closed.clone()));
+        }
 
-        // 监听外部请求
-        let conns = Arc::clone(&self.conns);
-        tokio::spawn(async move {
-            while let Some(conn) = orx.recv().await {
-                if tx.is_closed() { break; }
-                info!("coming new connection: {}", conn.id); // 接收来自入口的请求
+        // 会话过期时客户端可以重新登录，被撤销时则不应再重连
+        if let Some(session) = session {
+            let (tx, closed) = (tx.clone(), closed.clone());
+            tokio::spawn(async move {
+                let event = match tokio::select! {
+                    reason = session.ended() => reason,
+                    _ = tx.closed() => return,
+                } {
+                    SessionEnd::Expired => Event::Shutdown(ShutdownEvent { reason: "session expired".to_string() }),
+                    SessionEnd::Revoked => Event::Error(ErrorEvent { message: "session revoked".to_string() }),
+                };
+                let _ = tx.send(Ok(ListenNotification { event: Some(event) })).await;
+                closed.cancel();
+            });
+        }
 
-                // 发送给目标服务
-                conns.lock().await.insert(conn.id.clone(), conn.clone());
-                tx.send(Ok(ListenNotification { action: ACTION_COMING.to_string(), message: conn.id.clone() })).await.unwrap();
+        // 服务端即将退出时通知客户端
+        let mut shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                Ok(_) = shutdown.changed() => {
+                    let reason = shutdown.borrow().clone();
+                    let _ = tx.send(Ok(ListenNotification { event: Some(Event::Shutdown(ShutdownEvent { reason })) })).await;
+                }
+                _ = tx.closed() => {}
             }
-            debug!("orx exit");
         });
 
+        let stream = until_closed(rx, closed);
         Ok(Response::new(
-            Box::pin(ReceiverStream::new(rx)) as Self::ListenStream
+            Box::pin(stream) as Self::ListenStream
         ))
     }
 
     type TransferStream = Pin<Box<dyn Stream<Item=Result<TransferReply, Status>> + Send>>;
 
     async fn transfer(&self, req: Request<Streaming<TransferBody>>) -> Result<Response<Self::TransferStream>, Status> {
+        let tunnel_id = req.metadata().get(mux::TUNNEL_ID_KEY)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| Status::invalid_argument(format!("missing {} metadata", mux::TUNNEL_ID_KEY)))?
+            .to_string();
+        // 只有打开隧道的会话可以接入，隧道id会经管理接口列出
+        let session_id = req.extensions().get::<Session>().map(|session| session.id().to_string()).unwrap_or_default();
+        let out_rx = self.attach(&tunnel_id, &session_id, req.into_inner())?;
+        Ok(Response::new(
+            Box::pin(out_rx) as Self::TransferStream
+        ))
+    }
+}
+
+impl RSLServer {
+    /// Serves the connections of tunnel `tunnel_id` over `in_stream` and the returned stream.
+    #[allow(clippy::result_large_err)]
+    fn attach<S>(&self, tunnel_id: &str, session_id: &str, in_stream: S) -> Result<ReceiverStream<Result<TransferReply, Status>>, Status>
+        where S: Stream<Item=Result<TransferBody, Status>> + Send + Unpin + 'static
+    {
+        let (orx, entrypoint, with_visitor, streams, heartbeat, stats) = match self.tunnels.0.get(tunnel_id) {
+            Some(t) if t.info.session_id == session_id => (t.conns.lock().take(), t.info.entrypoint.clone(), t.with_visitor, t.streams.clone(), t.heartbeat.clone(), t.stats.clone()),
+            _ => return Err(Status::not_found("tunnel not found")),
+        };
+        let orx = orx.ok_or_else(|| Status::already_exists("tunnel already attached"))?;
+        debug!("tunnel {} attached to {}", tunnel_id, entrypoint);
+
+        // 一个隧道的所有连接共用这一条流
+        let (out_tx, out_rx) = mpsc::channel(128);
+        let idle_timeout = self.runtime.load().cfg.core.idle_timeout;
+        if idle_timeout > 0 {
+            tokio::spawn(reap_idle(streams.clone(), out_tx.clone(), Duration::from_secs(idle_timeout)));
+        }
+        tokio::spawn(accept_conns(orx, out_tx.clone(), streams.clone(), stats, entrypoint, with_visitor));
+        tokio::spawn(dispatch_frames(in_stream, streams, heartbeat, out_tx));
+        Ok(ReceiverStream::new(out_rx))
+    }
+}
+
+type ListenSender = Sender<Result<ListenNotification, Status>>;
+type ReplySender = Sender<Result<TransferReply, Status>>;
+
+// 关闭控制流前先发出已经排队的通知，例如告知客户端断开的原因
+fn until_closed(mut rx: Receiver<Result<ListenNotification, Status>>, closed: CancellationToken) -> impl Stream<Item=Result<ListenNotification, Status>> {
+    async_stream::stream! {
+        let mut closing = false;
+        loop {
+            let item = tokio::select! {
+                item = rx.recv() => item,
+                _ = closed.cancelled(), if !closing => {
+                    rx.close();
+                    closing = true;
+                    continue;
+                }
+            };
+            match item {
+                Some(item) => yield item,
+                None => break,
+            }
+        }
+    }
+}
+
+// 按间隔发送心跳，超时未收到回应则关闭控制流
+async fn ping_client(tx: ListenSender, last_pong: Activity, period: Duration, timeout: Duration, closed: CancellationToken) {
+    let mut ticker = interval(period);
+    ticker.tick().await;
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = tx.closed() => return,
+        }
+
+        if last_pong.idle() >= timeout {
+            info!("client did not answer heartbeats for {:?}, dropping tunnel", timeout);
+            let message = "heartbeat timeout".to_string();
+            let _ = tx.send(Ok(ListenNotification { event: Some(Event::Error(ErrorEvent { message })) })).await;
+            closed.cancel();
+            return;
+        }
+        if tx.send(Ok(ListenNotification { event: Some(Event::Ping(PingEvent {})) })).await.is_err() {
+            return;
+        }
+    }
+}
+
+// 接收来自入口的连接，直接开始转发，无需等待客户端确认
+async fn accept_conns(mut orx: Receiver<Connection>, out: ReplySender, streams: Arc<DashMap<String, MuxStream>>, stats: Arc<TunnelStats>, entrypoint: String, with_visitor: bool) {
+    while let Some(mut conn) = orx.recv().await {
+        conn.visitor.entrypoint = entrypoint.clone();
+        stats.connections.fetch_add(1, Ordering::Relaxed);
+
+        let (in_tx, in_rx) = mpsc::unbounded_channel();
+        let window = Window::default();
+        let recv_window = RecvWindow::default();
+        let activity = Activity::new();
+        streams.insert(conn.id.clone(), MuxStream {
+            inbound: in_tx,
+            recv_window: recv_window.clone(),
+            window: window.clone(),
+            closed_halves: AtomicU8::new(0),
+            activity: activity.clone(),
+            _active: METRICS.connection(),
+        });
+        info!("coming new connection: {} from {}, {} active on {}", conn.id, conn.visitor.remote_addr, streams.len(), entrypoint);
+
+        let visitor = if with_visitor { Some(conn.visitor.clone()) } else { None };
+        let open = TransferReply { conn_id: conn.id.clone(), status: TStatus::Ready as i32, visitor, ..Default::default() };
+        if out.send(Ok(open)).await.is_err() {
+            break;
+        }
+
+        // 通知Conn开始接收请求数据
         let (req_tx, req_rx) = mpsc::channel(128);
-        let conns = Arc::clone(&self.conns);
+        if conn.tx.send(XData::TX(req_tx)).await.is_err() {
+            streams.remove(&conn.id);
+            continue;
+        }
+
+        let (out_c, streams_c, stats_c, conn_id) = (out.clone(), streams.clone(), stats.clone(), conn.id.clone());
         tokio::spawn(async move {
-            let mut in_stream = req.into_inner();
-            while let Some(result) = in_stream.next().await {
-                let pr = result.unwrap();
-                let mg = conns.lock().await;
-                let conn = mg.get(pr.conn_id.as_str()).unwrap();
-                let ts = TStatus::from_i32(pr.status).unwrap();
-                match ts {
-                    TStatus::Ready => {
-                        debug!("connection ready to transfer: {}", pr.conn_id);
-                        let rtx = req_tx.clone();
-                        let (tx, mut rx) = mpsc::channel(128);
-                        conn.tx.send(XData::TX(tx)).await.unwrap(); // 通知Conn开始接收请求数据
-                        // 使用异步来接收数据，否则会导致mutex无法释放
-                        tokio::spawn(async move {
-                            // 这里是要发送出去的请求数据
-                            while let Some(req_data) = rx.recv().await {
-                                debug!("send req len: {:?}", req_data.len());
-                                if req_data.is_empty() {
-                                    break;
-                                }
-
-                                rtx.send(Ok(TransferReply { conn_id: pr.conn_id.clone(), req_data })).await.unwrap();
-                            }
-                            rtx.send(Ok(TransferReply { conn_id: pr.conn_id.clone(), req_data: vec![] })).await.unwrap();
-                            debug!("send req done");
-                        });
-                    }
-                    TStatus::Working => {
-                        // 返回接收到的响应数据
-                        debug!("receive resp len: {}", pr.resp_data.len());
-                        conn.tx.send(XData::Data(pr.resp_data)).await.unwrap();
-                    }
-                    TStatus::Done => {
-                        debug!("receive resp done");
-                        conn.tx.send(XData::Data(Vec::from("EOF"))).await.unwrap();
-                        break;
-                    }
+            send_requests(&conn_id, req_rx, window, activity, &stats_c, &out_c).await;
+            finish_half(&streams_c, &conn_id);
+        });
+
+        let (out_c, streams_c, stats_c) = (out.clone(), streams.clone(), stats.clone());
+        tokio::spawn(async move {
+            let conn_id = conn.id.clone();
+            recv_responses(conn, in_rx, recv_window, &stats_c, &out_c).await;
+            finish_half(&streams_c, &conn_id);
+        });
+    }
+    debug!("orx exit");
+}
+
+// 这里是要发送出去的请求数据
+async fn send_requests(conn_id: &str, mut rx: Receiver<XData>, window: Window, activity: Activity, stats: &TunnelStats, out: &ReplySender) {
+    let frame = |status: TStatus| TransferReply { conn_id: conn_id.to_string(), status: status as i32, ..Default::default() };
+    let mut next = rx.recv().await;
+    while let Some(xd) = next.take() {
+        let req_data = match xd {
+            XData::Data(req_data) => req_data,
+            XData::Reset => {
+                let _ = out.send(Ok(frame(TStatus::Reset))).await;
+                return;
+            }
+            XData::Error(reason) => {
+                let _ = out.send(Ok(TransferReply { reason, ..frame(TStatus::Error) })).await;
+                return;
+            }
+            _ => break,
+        };
+
+        // 合并已经排队的小块数据
+        let (req_data, rest) = mux::coalesce(req_data, &mut rx, |xd| match xd {
+            XData::Data(data) => Ok(data),
+            xd => Err(xd),
+        });
+        debug!("send req len: {:?}", req_data.len());
+        for chunk in mux::split(req_data) {
+            if !window.acquire(chunk.len()).await {
+                return;
+            }
+            stats.bytes_in.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            let data = TransferReply { req_data: chunk, ..frame(TStatus::Working) };
+            if out.send(Ok(data)).await.is_err() {
+                return;
+            }
+            activity.touch();
+        }
+        next = match rest {
+            Some(xd) => Some(xd),
+            None => rx.recv().await,
+        };
+    }
+    let _ = out.send(Ok(frame(TStatus::Done))).await;
+    debug!("send req done");
+}
+
+// 返回接收到的响应数据，交付后归还发送额度
+async fn recv_responses(conn: Connection, mut rx: UnboundedReceiver<TransferBody>, recv_window: RecvWindow, stats: &TunnelStats, out: &ReplySender) {
+    while let Some(pr) = rx.recv().await {
+        let xd = match TStatus::from_i32(pr.status) {
+            Some(TStatus::Working) => {
+                debug!("receive resp len: {}", pr.resp_data.len());
+                let n = pr.resp_data.len() as u32;
+                stats.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+                if conn.tx.send(XData::Data(pr.resp_data)).await.is_err() {
+                    // 访客已离开(如响应超时)，让客户端中止本地连接
+                    let _ = out.send(Ok(TransferReply { conn_id: conn.id.clone(), status: TStatus::Reset as i32, ..Default::default() })).await;
+                    return;
+                }
+                recv_window.release(n as usize);
+                let _ = out.send(Ok(TransferReply { conn_id: conn.id.clone(), status: TStatus::Window as i32, window: n, ..Default::default() })).await;
+                continue;
+            }
+            Some(TStatus::Done) => XData::Eof,
+            Some(TStatus::Reset) => XData::Reset,
+            Some(TStatus::Error) => XData::Error(pr.reason),
+            _ => continue,
+        };
+
+        debug!("receive resp done: {:?}", xd);
+        let _ = conn.tx.send(xd).await;
+        return;
+    }
+
+    // Transfer流断开时连接无法再继续，按异常中止处理
+    let _ = conn.tx.send(XData::Reset).await;
+}
+
+// 两个方向都结束后才移除连接
+fn finish_half(streams: &DashMap<String, MuxStream>, conn_id: &str) {
+    let last = streams.get(conn_id).map(|s| s.closed_halves.fetch_add(1, Ordering::AcqRel) == 1).unwrap_or(false);
+    if last {
+        if let Some((_, s)) = streams.remove(conn_id) {
+            s.window.close();
+        }
+    }
+}
+
+// 把客户端发来的帧分发给对应的连接
+async fn dispatch_frames(mut in_stream: impl Stream<Item=Result<TransferBody, Status>> + Unpin, streams: Arc<DashMap<String, MuxStream>>, heartbeat: Activity, out: ReplySender) {
+    while let Some(Ok(pr)) = in_stream.next().await {
+        if pr.status == TStatus::Pong as i32 {
+            heartbeat.touch();
+            continue;
+        }
+
+        let stream = match streams.get(pr.conn_id.as_str()) {
+            Some(stream) => stream,
+            None => {
+                debug!("frame for unknown connection: {}", pr.conn_id);
+                continue;
+            }
+        };
+
+        stream.activity.touch();
+        match TStatus::from_i32(pr.status) {
+            Some(TStatus::Window) => stream.window.release(pr.window as usize),
+            Some(TStatus::Working) if pr.resp_data.is_empty() => {}
+            Some(TStatus::Working) if !stream.recv_window.consume(pr.resp_data.len()) => {
+                drop(stream);
+                warn!("connection {} exceeded its window, resetting it", pr.conn_id);
+                if let Some((_, s)) = streams.remove(&pr.conn_id) {
+                    s.window.close();
+                }
+                let _ = out.send(Ok(TransferReply { conn_id: pr.conn_id, status: TStatus::Reset as i32, ..Default::default() })).await;
+            }
+            Some(TStatus::Working | TStatus::Done | TStatus::Reset | TStatus::Error) => {
+                let _ = stream.inbound.send(pr);
+            }
+            _ => debug!("unexpected frame {} for connection {}", pr.status, pr.conn_id),
+        }
+    }
+
+    // 客户端断开，释放所有连接
+    debug!("transfer stream closed");
+    close_streams(&streams);
+}
+
+// 连接被移除后，两个方向的转发任务都会随之退出
+fn close_streams(streams: &DashMap<String, MuxStream>) {
+    for stream in streams.iter() {
+        stream.window.close();
+    }
+    streams.clear();
+}
+
+// 关闭长时间没有数据往来的连接
+async fn reap_idle(streams: Arc<DashMap<String, MuxStream>>, out: ReplySender, timeout: Duration) {
+    let mut ticker = interval((timeout / 4).max(Duration::from_secs(1)));
+    while !out.is_closed() {
+        ticker.tick().await;
+        let idle: Vec<String> = streams.iter()
+            .filter(|s| s.activity.idle() >= timeout)
+            .map(|s| s.key().clone())
+            .collect();
+        for conn_id in idle {
+            if let Some((_, stream)) = streams.remove(&conn_id) {
+                stream.window.close();
+                METRICS.idle_closed();
+                info!("connection {} closed after being idle for {:?}", conn_id, timeout);
+                let _ = out.send(Ok(TransferReply { conn_id, status: TStatus::Reset as i32, ..Default::default() })).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::OnceLock;
+    use bytes::Bytes;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use serde_json::json;
+    use crate::mux::{INITIAL_WINDOW, MAX_FRAME_SIZE};
+    use crate::server::{Chain, Config};
+    use super::*;
+
+    // METRICS是进程级的，统计它的测试不能并行
+    async fn serial() -> tokio::sync::MutexGuard<'static, ()> {
+        static SERIAL: OnceLock<Mutex<()>> = OnceLock::new();
+        SERIAL.get_or_init(Default::default).lock().await
+    }
+
+    fn test_server() -> (RSLServer, Receiver<Payload>) {
+        let cfg: Config = serde_json::from_value(json!({
+            "core": { "debug": false, "bind_addr": "127.0.0.1:0", "auth_method": "token", "allow_ports": "18000-18100" },
+            "http": { "bind_addr": "127.0.0.1:0", "default_domain": "localtest.me" },
+            "tokens": {},
+        })).unwrap();
+        let (tx_http, mut rx_http) = mpsc::channel::<Payload>(1024);
+        // 和HTTP服务一样立即确认入口已打开
+        let (tx_payloads, payloads) = mpsc::channel(1024);
+        tokio::spawn(async move {
+            while let Some(mut payload) = rx_http.recv().await {
+                payload.opened(Ok(()));
+                if tx_payloads.send(payload).await.is_err() {
+                    break;
                 }
             }
         });
+        let (tx_tcp, _) = mpsc::channel(1);
+        let (_, shutdown) = watch::channel(String::new());
+        let runtime = Runtime { cfg: Arc::new(cfg), auth: Arc::new(Chain::new()) };
+        let server = RSLServer::new(Shared::new(Arc::new(runtime)), Shared::new(Default::default()), Default::default(), tx_tcp, tx_http, shutdown);
+        (server, payloads)
+    }
 
-        // 这里是需要发出去的请求数据
-        Ok(Response::new(
-            Box::pin(ReceiverStream::new(req_rx)) as Self::TransferStream
-        ))
+    #[tokio::test]
+    async fn port_ranges_include_their_upper_bound() {
+        let (server, _payloads) = test_server();
+        let policy = PolicyConfig { ports: Some("20000-20000".to_string()), ..Default::default() };
+        let state = AdminState::default();
+        let oeps = server.entrypoints.lock().await;
+        assert_eq!(server.build_tcp_addr(&oeps, Some(&policy), "bob", &state).unwrap(), "tcp://0.0.0.0:20000");
+        oeps.insert("tcp://0.0.0.0:20000".to_string());
+        assert!(server.build_tcp_addr(&oeps, Some(&policy), "bob", &state).is_err());
+    }
+
+    // 连接和隧道在后台任务中释放，稍等片刻
+    async fn eventually(cond: impl Fn() -> bool) -> bool {
+        for _ in 0..500 {
+            if cond() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
     }
-}
\ No newline at end of file
+
+    fn frame(conn_id: &str, status: TStatus, data: &'static [u8]) -> TransferBody {
+        TransferBody { conn_id: conn_id.to_string(), status: status as i32, resp_data: Bytes::from_static(data), ..Default::default() }
+    }
+
+    async fn next_reply(out: &mut ReceiverStream<Result<TransferReply, Status>>) -> TransferReply {
+        tokio::time::timeout(Duration::from_secs(5), out.next()).await.unwrap().unwrap().unwrap()
+    }
+
+    // 模拟一个访客和客户端完整地走完一次请求和响应
+    async fn round_trip(conns: &Sender<Connection>, out: &mut ReceiverStream<Result<TransferReply, Status>>, client: &Sender<Result<TransferBody, Status>>) {
+        let conn_id = random_string(16);
+        let (tx, mut rx) = mpsc::channel(128);
+        conns.send(Connection { id: conn_id.clone(), tx, visitor: Default::default() }).await.unwrap();
+        assert_eq!(next_reply(out).await.status, TStatus::Ready as i32);
+
+        let req_tx = match rx.recv().await {
+            Some(XData::TX(req_tx)) => req_tx,
+            other => panic!("unexpected {:?}", other),
+        };
+        req_tx.send(XData::Data(Bytes::from_static(b"ping"))).await.unwrap();
+        drop(req_tx);
+        let data = next_reply(out).await;
+        assert_eq!((data.status, &data.req_data[..]), (TStatus::Working as i32, &b"ping"[..]));
+        assert_eq!(next_reply(out).await.status, TStatus::Done as i32);
+
+        client.send(Ok(frame(&conn_id, TStatus::Working, b"pong"))).await.unwrap();
+        client.send(Ok(frame(&conn_id, TStatus::Done, b""))).await.unwrap();
+        assert!(matches!(rx.recv().await, Some(XData::Data(data)) if data == "pong"));
+        assert!(matches!(rx.recv().await, Some(XData::Eof)));
+        assert_eq!(next_reply(out).await.status, TStatus::Window as i32);
+    }
+
+    #[tokio::test]
+    async fn tunnels_and_connections_are_released() {
+        let _serial = serial().await;
+        let (server, mut payloads) = test_server();
+        for round in 0..20 {
+            let param = ListenParam {
+                protocol: Protocol::Http as i32,
+                subdomain: format!("soak{}", round),
+                protocol_version: protocol::PROTOCOL_VERSION,
+                capabilities: vec![],
+            };
+            let mut listen = server.listen(Request::new(param)).await.unwrap().into_inner();
+            let tunnel_id = match listen.next().await {
+                Some(Ok(ListenNotification { event: Some(Event::Ready(ready)) })) => ready.tunnel_id,
+                other => panic!("unexpected {:?}", other),
+            };
+            let conns = payloads.recv().await.unwrap().tx;
+            let (client, frames) = mpsc::channel(128);
+            let mut out = server.attach(&tunnel_id, "", ReceiverStream::new(frames)).unwrap();
+
+            for _ in 0..10 {
+                round_trip(&conns, &mut out, &client).await;
+            }
+            assert!(eventually(|| server.tunnels.0.get(&tunnel_id).unwrap().streams.is_empty()).await);
+            assert!(eventually(|| METRICS.snapshot().connections == 0 && METRICS.snapshot().tunnels == 1).await);
+
+            // 客户端断开，隧道和入口随之注销
+            drop(listen);
+            drop(client);
+            assert!(payloads.recv().await.unwrap().tx.is_closed());
+        }
+
+        assert!(eventually(|| server.tunnels.0.is_empty()).await);
+        assert!(server.entrypoints.lock().await.is_empty());
+        let metrics = METRICS.snapshot();
+        assert_eq!((metrics.tunnels, metrics.connections), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn dispatch_survives_random_frames() {
+        let _serial = serial().await;
+        let mut rng = StdRng::seed_from_u64(36);
+        let streams: Arc<DashMap<String, MuxStream>> = Default::default();
+        let mut inbound = HashMap::new();
+        for i in 0..8 {
+            let (tx, rx) = mpsc::unbounded_channel();
+            streams.insert(format!("c{}", i), MuxStream {
+                inbound: tx,
+                recv_window: Default::default(),
+                window: Default::default(),
+                closed_halves: AtomicU8::new(0),
+                activity: Activity::new(),
+                _active: METRICS.connection(),
+            });
+            inbound.insert(format!("c{}", i), rx);
+        }
+        let (client, frames) = mpsc::channel(4096);
+        let (out_tx, mut out_rx) = mpsc::channel(4096);
+        let dispatch = tokio::spawn(dispatch_frames(ReceiverStream::new(frames), streams.clone(), Activity::new(), out_tx));
+
+        // 记录每个连接收到但还没归还额度的字节，超出窗口的连接应被重置
+        let mut received: HashMap<String, usize> = HashMap::new();
+        let mut reset = HashSet::new();
+        for _ in 0..2000 {
+            let conn_id = format!("c{}", rng.gen_range(0..10));
+            let status = rng.gen_range(-1..=7);
+            let mut data = vec![0u8; if rng.gen_bool(0.3) { 0 } else { rng.gen_range(1..=MAX_FRAME_SIZE) }];
+            rng.fill(&mut data[..]);
+            if status == TStatus::Working as i32 && !data.is_empty() && inbound.contains_key(&conn_id) && !reset.contains(&conn_id) {
+                let total = received.entry(conn_id.clone()).or_default();
+                if *total + data.len() > INITIAL_WINDOW {
+                    reset.insert(conn_id.clone());
+                } else {
+                    *total += data.len();
+                }
+            }
+            let frame = TransferBody { conn_id, status, resp_data: data.into(), window: rng.gen(), ..Default::default() };
+            client.send(Ok(frame)).await.unwrap();
+        }
+        drop(client);
+        dispatch.await.unwrap();
+        assert!(streams.is_empty());
+
+        let mut announced = HashSet::new();
+        while let Ok(Ok(reply)) = out_rx.try_recv() {
+            assert_eq!(reply.status, TStatus::Reset as i32);
+            announced.insert(reply.conn_id);
+        }
+        assert_eq!(announced, reset);
+        for (conn_id, mut rx) in inbound {
+            let mut bytes = 0;
+            while let Ok(frame) = rx.try_recv() {
+                if frame.status == TStatus::Working as i32 {
+                    bytes += frame.resp_data.len();
+                }
+            }
+            assert_eq!(bytes, received.get(&conn_id).copied().unwrap_or_default(), "{}", conn_id);
+            assert!(bytes <= INITIAL_WINDOW);
+        }
+    }
+}
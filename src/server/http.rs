@@ -1,35 +1,48 @@
-use std::collections::HashMap;
 use std::fmt::Error;
 use std::future::Future;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
+use bytes::{Bytes, BytesMut};
+use dashmap::DashMap;
 use http::{HeaderMap, HeaderValue, Request, Response, StatusCode};
 use http::header::HeaderName;
 use http::response::Builder;
 use hyper::{Body};
 use hyper::service::{Service};
-use log::{debug, info};
-use tokio::io::AsyncWriteExt;
-use tokio::sync::{mpsc, Mutex};
+use log::{debug, info, warn};
+use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::timeout;
 use tokio_stream::wrappers::ReceiverStream;
-use url::Url;
 use crate::random_string;
-use crate::server::{Connection, Payload, XData};
+use crate::server::{Connection, entrypoint_addr, Payload, Shared, XData};
 use crate::server::config::HTTPConfig;
+use crate::server::api::{HttpRequest, Visitor};
 
 static NOTFOUND: &[u8] = b"vHost Not Found";
+static GATEWAY_TIMEOUT: &[u8] = b"Local Service Timeout";
+// 本地服务的响应头超过这个长度时返回502
+const MAX_HEADER_SIZE: usize = 64 * 1024;
 
 #[derive(Clone)]
 pub struct HttpServer {
-    pub inner: Arc<Mutex<HttpServerInner>>,
+    pub inner: Arc<HttpServerInner>,
+
+    remote_addr: Option<SocketAddr>,
 }
 
 impl HttpServer {
     pub fn new(cfg: HTTPConfig) -> Self {
-        Self { inner: Arc::new(Mutex::new(HttpServerInner::new(cfg))) }
+        Self { inner: Arc::new(HttpServerInner::new(cfg)), remote_addr: None }
+    }
+
+    /// Returns a handle serving a single visitor connection from `addr`.
+    pub fn with_remote_addr(&self, addr: SocketAddr) -> Self {
+        Self { inner: Arc::clone(&self.inner), remote_addr: Some(addr) }
     }
 }
 
@@ -42,15 +55,20 @@ impl Service<Request<Body>> for HttpServer {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, req: Request<Body>) -> Self::Future {
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
         let inner = Arc::clone(&self.inner);
         let method = req.method().clone();
         let uri = req.uri().clone();
         let version = req.version();
+        let remote_addr = self.remote_addr;
+        if let Some(addr) = remote_addr {
+            set_forwarded_for(req.headers_mut(), addr);
+        }
         let res = async move {
-            let resp = inner.lock().await.proxy(req).await.unwrap();
+            let resp = inner.proxy(req, remote_addr).await?;
             // 输出访问日志
-            info!("\"{} {} {:?}\" {} {}", method, uri, version,
+            let remote = remote_addr.map(|a| a.ip().to_string()).unwrap_or_else(|| "-".to_string());
+            info!("{} \"{} {} {:?}\" {} {}", remote, method, uri, version,
             resp.status(), "-");
             Ok(resp)
         };
@@ -59,23 +77,64 @@ impl Service<Request<Body>> for HttpServer {
     }
 }
 
-pub struct HttpServerInner {
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// 追加访客地址到X-Forwarded-For，保留上游代理已写入的链路
+fn set_forwarded_for(headers: &mut HeaderMap, addr: SocketAddr) {
+    let ip = addr.ip().to_string();
+    let value = match headers.get("X-Forwarded-For").and_then(|v| v.to_str().ok()) {
+        Some(prev) if !prev.is_empty() => format!("{}, {}", prev, ip),
+        _ => ip,
+    };
+    if let Ok(v) = HeaderValue::try_from(value) {
+        headers.insert("X-Forwarded-For", v);
+    }
+}
+
+/// The reloadable part of the HTTP server.
+struct HttpSettings {
     cfg: HTTPConfig,
+    unavailable_page: Option<String>,
+}
 
-    vhosts: HashMap<String, Sender<Connection>>,
+impl HttpSettings {
+    fn new(cfg: HTTPConfig) -> Self {
+        let unavailable_page = cfg.unavailable_page.as_ref().and_then(|path| {
+            std::fs::read_to_string(path)
+                .map_err(|e| warn!("failed to load unavailable_page {}: {}", path, e))
+                .ok()
+        });
+        HttpSettings { cfg, unavailable_page }
+    }
+}
+
+pub struct HttpServerInner {
+    settings: Shared<HttpSettings>,
+
+    // 按Host路由，读多写少，查找时不持有跨await的锁
+    vhosts: DashMap<String, Sender<Connection>>,
 }
 
 impl HttpServerInner {
     pub fn new(cfg: HTTPConfig) -> Self {
-        HttpServerInner { cfg, vhosts: Default::default() }
+        HttpServerInner { settings: Shared::new(Arc::new(HttpSettings::new(cfg))), vhosts: Default::default() }
     }
 
-    pub async fn event_handler(&mut self, pl: Payload) {
-        let u = Url::parse(pl.entrypoint.as_str()).unwrap();
-        let mut host = u.host_str().unwrap().to_string();
-        if let Some(port) = u.port() {
-            host = format!("{}:{}", host, port);
-        }
+    /// Applies new settings to the following requests, `bind_addr` needs a restart.
+    pub fn reload(&self, cfg: HTTPConfig) {
+        self.settings.store(Arc::new(HttpSettings::new(cfg)));
+    }
+
+    pub async fn event_handler(&self, mut pl: Payload) {
+        let host = match entrypoint_addr(&pl.entrypoint) {
+            Some(host) => host,
+            None => {
+                warn!("invalid entrypoint: {}", pl.entrypoint);
+                return;
+            }
+        };
 
         if pl.tx.is_closed() {
             debug!("host {:?} removed", host);
@@ -84,15 +143,15 @@ impl HttpServerInner {
         }
 
         debug!("host {:?} registered", host);
-        self.vhosts.insert(host, pl.tx);
+        self.vhosts.insert(host, pl.tx.clone());
+        pl.opened(Ok(()));
         debug!("vhosts {:?}", self.vhosts);
     }
 
-    async fn vhost_match(&self, headers: HeaderMap) -> Option<&Sender<Connection>> {
-        let host = headers.get("Host").unwrap().to_str().unwrap().to_string();
+    fn vhost_match(&self, headers: &HeaderMap) -> Option<Sender<Connection>> {
+        let host = headers.get("Host")?.to_str().ok()?;
         debug!("host: {}", host);
-        debug!("vhosts: {:?}", self.vhosts);
-        return self.vhosts.get(host.as_str());
+        self.vhosts.get(host).map(|tx| tx.clone())
     }
 
     fn vhost_not_found(&self) -> Response<Body> {
@@ -102,16 +161,36 @@ impl HttpServerInner {
             .unwrap()
     }
 
-    async fn proxy(&self, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-        let vhost = self.vhost_match(req.headers().clone()).await;
-        if vhost.is_none() {
-            return Ok(self.vhost_not_found());
-        }
+    fn bad_gateway(&self, reason: &str) -> Response<Body> {
+        let builder = Response::builder().status(StatusCode::BAD_GATEWAY);
+        match &self.settings.load().unavailable_page {
+            Some(page) => builder
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(page.replace("{reason}", &html_escape(reason)).into()),
+            None => builder.body(format!("Local Service Unavailable: {}", reason).into()),
+        }.unwrap()
+    }
+
+    fn gateway_timeout(&self) -> Response<Body> {
+        Response::builder()
+            .status(StatusCode::GATEWAY_TIMEOUT)
+            .body(GATEWAY_TIMEOUT.into())
+            .unwrap()
+    }
+
+    async fn proxy(&self, req: Request<Body>, remote_addr: Option<SocketAddr>) -> Result<Response<Body>, hyper::Error> {
+        let vhost = match self.vhost_match(req.headers()) {
+            Some(vhost) => vhost,
+            None => return Ok(self.vhost_not_found()),
+        };
 
         // 通知客户端开始连接已就绪
         let req_id = random_string(64);
         let (tx, rx) = mpsc::channel(128);
-        vhost.unwrap().send(Connection { id: req_id, tx }).await.unwrap();
+        let visitor = Self::visitor_of(&req, remote_addr);
+        if vhost.send(Connection { id: req_id, tx, visitor }).await.is_err() {
+            return Ok(self.bad_gateway("tunnel is closed"));
+        }
         debug!("send done");
 
         // 准备接收客户端发送的数据并转发
@@ -121,94 +200,256 @@ impl HttpServerInner {
             Self::transfer(rx, req, htx, btx).await;
         });
 
-        // 解析Headers
-        let headers = hrx.recv().await.unwrap();
-        let builder = Self::init_builder_from_headers(headers);
+        // 解析Headers，本地服务迟迟不响应时返回504
+        let builder = match timeout(Duration::from_secs(self.settings.load().cfg.response_timeout), hrx.recv()).await {
+            Ok(Some(Ok(headers))) => match Self::init_builder_from_headers(headers) {
+                Ok(builder) => builder,
+                Err(reason) => return Ok(self.bad_gateway(&reason)),
+            },
+            Ok(Some(Err(reason))) => return Ok(self.bad_gateway(&reason)),
+            Ok(None) => return Ok(self.bad_gateway("local service closed the connection without a response")),
+            Err(_) => return Ok(self.gateway_timeout()),
+        };
         Ok(builder.body(Body::wrap_stream(ReceiverStream::new(brx))).unwrap())
     }
 
-    async fn transfer(mut rx: Receiver<XData>, req: Request<Body>, htx: Sender<Vec<u8>>, btx: Sender<Result<Vec<u8>, Error>>) {
-        let req_bytes = Self::build_raw_request(req).await.unwrap();
+    fn visitor_of(req: &Request<Body>, remote_addr: Option<SocketAddr>) -> Visitor {
+        let host = req.headers().get("Host").and_then(|h| h.to_str().ok()).unwrap_or_default();
+        let path = req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+        Visitor {
+            remote_addr: remote_addr.map(|a| a.to_string()).unwrap_or_default(),
+            entrypoint: String::new(),
+            http: Some(HttpRequest { method: req.method().to_string(), host: host.to_string(), path: path.to_string() }),
+        }
+    }
+
+    async fn transfer(mut rx: Receiver<XData>, req: Request<Body>, htx: Sender<Result<Vec<u8>, String>>, btx: Sender<Result<Bytes, Error>>) {
+        let req_bytes = match Self::build_raw_request(req).await {
+            Ok(req_bytes) => req_bytes,
+            Err(e) => {
+                debug!("read request failed: {}", e);
+                let _ = htx.send(Err(format!("failed to read request: {}", e))).await;
+                return;
+            }
+        };
+        // 响应头可能跨多个数据帧，收齐之前先缓存
+        let mut head = BytesMut::new();
+        let mut header_done = false;
         while let Some(xd) = rx.recv().await {
             match xd {
                 XData::TX(tx) => {
                     debug!("start send ProxyRequest");
-                    tx.send(req_bytes.clone()).await.unwrap();
+                    if tx.send(XData::Data(req_bytes.clone())).await.is_err() {
+                        break;
+                    }
                 }
                 XData::Data(resp) => {
-                    if resp.eq("EOF".as_bytes()) {
-                        break;
+                    let mut body = resp;
+                    if !header_done {
+                        // 只需从上次缓存末尾的前3个字节开始查找
+                        let from = head.len().saturating_sub(3);
+                        head.extend_from_slice(&body);
+                        match head[from..].windows(4).position(|w| w == b"\r\n\r\n") {
+                            Some(pos) => {
+                                let header = head.split_to(from + pos + 4);
+                                if htx.send(Ok(header.to_vec())).await.is_err() {
+                                    break; // 已超时，访客不再等待
+                                }
+                                body = head.split().freeze();
+                                header_done = true;
+                            }
+                            None if head.len() > MAX_HEADER_SIZE => {
+                                let _ = htx.send(Err("response header from local service is too large".to_string())).await;
+                                break;
+                            }
+                            None => continue,
+                        }
+                        if body.is_empty() {
+                            continue;
+                        }
                     }
 
-                    let resp_length = resp.len();
-                    let mut header_length = 1024;
-                    if resp_length < header_length {
-                        header_length = resp_length;
-                    }
-                    let mut body = resp.clone();
-                    if let Some(split_idx) = String::from_utf8_lossy(&resp[..header_length]).find("\r\n\r\n") {
-                        let header = resp[..split_idx + 2].to_owned();
-                        htx.send(header).await.unwrap();
-                        body = resp[split_idx + 4..].to_owned();
+                    let br: Result<Bytes, Error> = Ok(body);
+                    if btx.send(br).await.is_err() {
+                        break;
                     }
-
-                    let br: Result<Vec<u8>, Error> = Ok(body);
-                    btx.send(br).await.unwrap();
+                }
+                XData::Eof => break,
+                XData::Reset | XData::Error(_) => {
+                    // 还没有响应头时由proxy返回502，否则中断响应体
+                    let reason = match xd {
+                        XData::Error(reason) => reason,
+                        _ => "connection reset by local service".to_string(),
+                    };
+                    debug!("transfer aborted: {}", reason);
+                    let _ = htx.send(Err(reason)).await;
+                    let _ = btx.send(Err(Error)).await;
+                    break;
                 }
             }
         }
     }
 
-    async fn build_raw_request(req: Request<Body>) -> anyhow::Result<Vec<u8>> {
-        let mut buf = format!("{} {} {:?}\r\n", req.method(), req.uri(), req.version());
+    async fn build_raw_request(req: Request<Body>) -> anyhow::Result<Bytes> {
+        // 头部的值不一定是合法的UTF-8，按原始字节拼接
+        let mut data = BytesMut::new();
+        data.extend_from_slice(format!("{} {} {:?}\r\n", req.method(), req.uri(), req.version()).as_bytes());
         for (name, val) in req.headers() {
-            buf.push_str(&format!("{}: {}\r\n", name.as_str(), val.to_str().unwrap()));
+            data.extend_from_slice(name.as_str().as_bytes());
+            data.extend_from_slice(b": ");
+            data.extend_from_slice(val.as_bytes());
+            data.extend_from_slice(b"\r\n");
         }
-        buf.push_str("\r\n");
+        data.extend_from_slice(b"\r\n");
         let body = hyper::body::to_bytes(req.into_body()).await?;
-        let mut data = buf.clone().into_bytes();
-        data.write_all(body.as_ref()).await.unwrap(); // todo 改成流式发送
-        Ok(data)
+        data.extend_from_slice(&body); // todo 改成流式发送
+        Ok(data.freeze())
     }
 
-    fn init_builder_from_headers(header: Vec<u8>) -> Builder {
+    fn init_builder_from_headers(header: Vec<u8>) -> Result<Builder, String> {
         debug!("raw_headers:{:?}", String::from_utf8_lossy(header.as_slice()));
         let mut headers = [httparse::EMPTY_HEADER; 64];
         let mut resp = httparse::Response::new(&mut headers);
-        resp.parse(header.as_slice()).unwrap();
-
-        // 设置Headers
-        let mut builder = Response::builder().status(resp.code.unwrap());
-        let header_map = builder.headers_mut().unwrap();
-        for h in headers.iter().clone() {
-            if h.name.is_empty() {
-                continue;
-            }
+        match resp.parse(header.as_slice()) {
+            Ok(httparse::Status::Complete(_)) => {}
+            Ok(httparse::Status::Partial) => return Err("incomplete response header from local service".to_string()),
+            Err(e) => return Err(format!("invalid response header from local service: {}", e)),
+        }
+        let status = resp.code.and_then(|code| StatusCode::from_u16(code).ok())
+            .ok_or_else(|| "invalid status code from local service".to_string())?;
 
-            header_map.insert(HeaderName::try_from(h.name).unwrap(), HeaderValue::try_from(h.value).unwrap());
+        // 设置Headers，跳过不合法的头部
+        let mut builder = Response::builder().status(status);
+        if let Some(header_map) = builder.headers_mut() {
+            for h in resp.headers.iter() {
+                match (HeaderName::try_from(h.name), HeaderValue::try_from(h.value)) {
+                    (Ok(name), Ok(value)) => {
+                        header_map.append(name, value);
+                    }
+                    _ => debug!("skip invalid header: {}", h.name),
+                }
+            }
+            debug!("final_headers:{:?}", header_map);
         }
-        debug!("final_headers:{:?}", header_map);
-        builder
+        Ok(builder)
     }
 }
 
-#[derive(Clone)]
-pub struct MakeHttpServer {
-    pub http_server: HttpServer,
-}
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use futures::future::join_all;
+    use super::*;
 
-impl<T> Service<T> for MakeHttpServer {
-    type Response = HttpServer;
-    type Error = hyper::Error;
-    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+    const HOST_STALLED: &str = "stalled.localtest.me:8423";
+    const HOST_FAST: &str = "fast.localtest.me:8423";
 
-    fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
+    fn server() -> HttpServerInner {
+        HttpServerInner::new(HTTPConfig {
+            bind_addr: "127.0.0.1:0".to_string(),
+            default_domain: "localtest.me:8423".to_string(),
+            unavailable_page: None,
+            response_timeout: 60,
+        })
+    }
+
+    async fn register(server: &HttpServerInner, host: &str) -> Receiver<Connection> {
+        let (tx, rx) = mpsc::channel(128);
+        server.event_handler(Payload { tx, entrypoint: format!("http://{}", host), ready: None }).await;
+        rx
+    }
+
+    // 模拟客户端：读完请求后立即返回响应
+    async fn serve(mut conns: Receiver<Connection>) {
+        while let Some(conn) = conns.recv().await {
+            tokio::spawn(async move {
+                let (req_tx, mut req_rx) = mpsc::channel(128);
+                conn.tx.send(XData::TX(req_tx)).await.unwrap();
+                req_rx.recv().await.unwrap();
+                conn.tx.send(XData::Data(Bytes::from_static(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"))).await.unwrap();
+                conn.tx.send(XData::Eof).await.unwrap();
+            });
+        }
     }
 
-    fn call(&mut self, _: T) -> Self::Future {
-        let inner = self.http_server.clone();
-        let fut = async move { Ok(inner) };
-        Box::pin(fut)
+    // 模拟客户端：把响应拆成多个数据帧发送
+    async fn serve_frames(mut conns: Receiver<Connection>, frames: Vec<Vec<u8>>) {
+        let conn = conns.recv().await.unwrap();
+        let (req_tx, mut req_rx) = mpsc::channel(128);
+        conn.tx.send(XData::TX(req_tx)).await.unwrap();
+        req_rx.recv().await.unwrap();
+        for frame in frames {
+            if conn.tx.send(XData::Data(frame.into())).await.is_err() {
+                return;
+            }
+        }
+        let _ = conn.tx.send(XData::Eof).await;
+    }
+
+    async fn proxy_frames(frames: Vec<Vec<u8>>) -> Response<Body> {
+        let server = server();
+        let conns = register(&server, HOST_FAST).await;
+        tokio::spawn(serve_frames(conns, frames));
+        server.proxy(request(HOST_FAST), None).await.unwrap()
+    }
+
+    fn request(host: &str) -> Request<Body> {
+        Request::builder().uri("/").header("Host", host).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn stalled_vhost_does_not_block_others() {
+        let server = Arc::new(server());
+        // 这个隧道收下连接后再也不响应
+        let mut stalled = register(&server, HOST_STALLED).await;
+        let fast = register(&server, HOST_FAST).await;
+        tokio::spawn(serve(fast));
+
+        let s = server.clone();
+        let mut pending = tokio::spawn(async move { s.proxy(request(HOST_STALLED), None).await });
+        let held = stalled.recv().await.unwrap();
+
+        let responses = join_all((0..50).map(|_| server.proxy(request(HOST_FAST), None)));
+        let responses = timeout(Duration::from_secs(5), responses).await.expect("fast vhost was blocked");
+        for resp in responses {
+            let resp = resp.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), "ok");
+        }
+        assert!((&mut pending).now_or_never().is_none());
+        drop(held);
+    }
+
+    #[tokio::test]
+    async fn unknown_host_is_not_found() {
+        let server = server();
+        let resp = server.proxy(request("nobody.localtest.me:8423"), None).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn headers_split_across_frames() {
+        let frames = ["HTTP/1.1 200 OK\r\nX-Test: a\r", "\n\r", "\nhel", "lo"];
+        let resp = proxy_frames(frames.iter().map(|f| f.as_bytes().to_vec()).collect()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["X-Test"], "a");
+        assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), "hello");
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn long_headers_are_parsed() {
+        let header = format!("HTTP/1.1 201 Created\r\nX-Long: {}\r\n\r\nok", "x".repeat(4000));
+        let (first, second) = header.as_bytes().split_at(3000);
+        let resp = proxy_frames(vec![first.to_vec(), second.to_vec()]).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(resp.headers()["X-Long"].len(), 4000);
+        assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn oversized_headers_are_bad_gateway() {
+        let frames = vec![b"HTTP/1.1 200 OK\r\n".to_vec(), vec![b'x'; MAX_HEADER_SIZE]];
+        let resp = proxy_frames(frames).await;
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+    }
+}
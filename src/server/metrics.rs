@@ -0,0 +1,70 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Process wide counters of the tunnels and connections being served.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    tunnels: AtomicU64,
+    connections: AtomicU64,
+    connections_total: AtomicU64,
+    idle_closed_total: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics {
+    tunnels: AtomicU64::new(0),
+    connections: AtomicU64::new(0),
+    connections_total: AtomicU64::new(0),
+    idle_closed_total: AtomicU64::new(0),
+};
+
+/// A point in time copy of [`Metrics`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub tunnels: u64,
+    pub connections: u64,
+    pub connections_total: u64,
+    pub idle_closed_total: u64,
+}
+
+/// Keeps a gauge incremented for as long as it is alive.
+#[derive(Debug)]
+pub struct Active(&'static AtomicU64);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            tunnels: self.tunnels.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+            connections_total: self.connections_total.load(Ordering::Relaxed),
+            idle_closed_total: self.idle_closed_total.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn tunnel(&'static self) -> Active {
+        self.tunnels.fetch_add(1, Ordering::Relaxed);
+        Active(&self.tunnels)
+    }
+
+    pub(crate) fn connection(&'static self) -> Active {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        Active(&self.connections)
+    }
+
+    pub(crate) fn idle_closed(&self) {
+        self.idle_closed_total.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for MetricsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tunnels={} connections={} connections_total={} idle_closed_total={}",
+               self.tunnels, self.connections, self.connections_total, self.idle_closed_total)
+    }
+}
@@ -1,13 +1,35 @@
+mod admin;
+mod auth;
 mod grpc;
 mod config;
 mod http;
+mod metrics;
+mod oidc;
+mod policy;
+mod proxy_protocol;
+mod ratelimit;
+mod reload;
+mod session;
+mod state;
 mod tcp;
+mod token;
 mod transport;
 mod tunnel;
 
-pub use self::config::Config;
+pub use self::admin::*;
+pub use self::auth::*;
+pub use self::config::{Config, TokenConfig, TokenEntry};
 pub use self::grpc::*;
 pub use self::http::*;
+pub use self::metrics::*;
+pub use self::oidc::*;
+pub use self::policy::*;
+pub use self::proxy_protocol::*;
+pub use self::ratelimit::*;
+pub use self::reload::*;
+pub use self::session::*;
+pub use self::state::*;
 pub use self::tcp::*;
+pub use self::token::*;
 pub use self::transport::*;
 pub use self::tunnel::*;
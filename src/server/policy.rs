@@ -0,0 +1,244 @@
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use tonic::Status;
+use crate::random_string;
+use crate::server::api::Protocol;
+use crate::server::config::PolicyConfig;
+use crate::server::Identity;
+
+const PROTOCOLS: [&str; 2] = ["http", "tcp"];
+
+impl PolicyConfig {
+    /// Whether the policy names the user or one of its groups.
+    pub fn applies_to(&self, identity: &Identity) -> bool {
+        self.users.iter().any(|u| u == "*" || *u == identity.username)
+            || self.groups.iter().any(|g| identity.groups.contains(g))
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.users.is_empty() && self.groups.is_empty() {
+            return Err("policies must name users or groups".to_string());
+        }
+        if let Some(p) = self.protocols.iter().find(|p| !PROTOCOLS.contains(&p.as_str())) {
+            return Err(format!("unknown protocol {} in policy, expected http or tcp", p));
+        }
+        if let Some(ports) = &self.ports {
+            parse_port_range(ports).ok_or_else(|| format!("invalid ports {} in policy", ports))?;
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn check_protocol(&self, protocol: Protocol, username: &str) -> Result<(), Status> {
+        let name = protocol_name(protocol);
+        if !self.protocols.is_empty() && !self.protocols.iter().any(|p| p == name) {
+            return Err(Status::permission_denied(format!("user {} may not listen with {}", username, name)));
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn check_subdomain(&self, subdomain: &str, username: &str) -> Result<(), Status> {
+        // `*`也能匹配`.`，先排除多级的子域名
+        if !is_dns_label(subdomain) {
+            return Err(Status::invalid_argument(format!("subdomain {} is not a valid DNS label", subdomain)));
+        }
+        let patterns = self.subdomain_patterns(username);
+        if !patterns.is_empty() && !patterns.iter().any(|p| glob_match(p, &subdomain.to_lowercase())) {
+            return Err(Status::permission_denied(format!(
+                "subdomain {} is not allowed for user {}, expected {}", subdomain, username, patterns.join(" or "))));
+        }
+        Ok(())
+    }
+
+    /// A random subdomain matching the first pattern, when subdomains are restricted.
+    pub fn random_subdomain(&self, username: &str) -> Option<String> {
+        self.subdomain_patterns(username).first().map(|p| p.replace('*', &random_string(8).to_lowercase()))
+    }
+
+    /// The port range of the policy, `core.allow_ports` applies if `None`.
+    pub fn port_range(&self) -> Option<(u16, u16)> {
+        self.ports.as_deref().and_then(parse_port_range)
+    }
+
+    fn subdomain_patterns(&self, username: &str) -> Vec<String> {
+        self.subdomains.iter().map(|p| p.replace("{username}", username).to_lowercase()).collect()
+    }
+}
+
+/// The first policy applying to `identity`.
+pub fn find_policy<'a>(policies: &'a [PolicyConfig], identity: &Identity) -> Option<&'a PolicyConfig> {
+    policies.iter().find(|p| p.applies_to(identity))
+}
+
+/// Whether `label` is a single DNS label: 1 to 63 letters, digits or inner hyphens.
+pub fn is_dns_label(label: &str) -> bool {
+    (1..=63).contains(&label.len())
+        && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !label.starts_with('-') && !label.ends_with('-')
+}
+
+/// Parses a port range like `18000-19000`.
+pub fn parse_port_range(range: &str) -> Option<(u16, u16)> {
+    let (min, max) = range.split_once('-')?;
+    let (min, max) = (min.trim().parse().ok()?, max.trim().parse().ok()?);
+    (min <= max).then_some((min, max))
+}
+
+fn protocol_name(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Http => "http",
+        Protocol::Tcp => "tcp",
+    }
+}
+
+// 只支持`*`通配符
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match text.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let last = match parts.split_last() {
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(i) => rest = &rest[i + part.len()..],
+                    None => return false,
+                }
+            }
+            last
+        }
+        None => return rest.is_empty(),
+    };
+    rest.ends_with(last)
+}
+
+/// Counts the tunnels of each user to enforce `max_tunnels`.
+#[derive(Debug, Clone, Default)]
+pub struct UserTunnels(Arc<DashMap<String, usize>>);
+
+impl UserTunnels {
+    /// Registers a tunnel of `username` unless it already has `max` of them.
+    pub fn acquire(&self, username: &str, max: usize) -> Option<UserTunnel> {
+        let mut count = self.0.entry(username.to_string()).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(UserTunnel { tunnels: self.clone(), username: username.to_string() })
+    }
+}
+
+/// A tunnel counted by [`UserTunnels`], released on drop.
+#[derive(Debug)]
+pub struct UserTunnel {
+    tunnels: UserTunnels,
+    username: String,
+}
+
+impl Drop for UserTunnel {
+    fn drop(&mut self) {
+        self.tunnels.0.remove_if_mut(&self.username, |_, count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(subdomains: &[&str]) -> PolicyConfig {
+        PolicyConfig {
+            users: vec!["*".to_string()],
+            subdomains: subdomains.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn glob_matches_stars_only() {
+        assert!(glob_match("bob", "bob"));
+        assert!(!glob_match("bob", "bobby"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("bob-*", "bob-"));
+        assert!(glob_match("bob-*", "bob-api"));
+        assert!(!glob_match("bob-*", "alice-api"));
+        assert!(glob_match("*-bob", "api-bob"));
+        assert!(glob_match("a*b*c", "abc"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("a*b*c", "axxcyyb"));
+        // 前后缀不能重叠使用同一段字符
+        assert!(!glob_match("ab*ba", "aba"));
+        assert!(glob_match("*.*", "a.b"));
+        assert!(!glob_match("?", "a"));
+    }
+
+    #[test]
+    fn subdomains_must_match_a_single_label() {
+        let policy = policy(&["{username}-*", "demo"]);
+        assert!(policy.check_subdomain("bob-api", "bob").is_ok());
+        assert!(policy.check_subdomain("BOB-API", "bob").is_ok());
+        assert!(policy.check_subdomain("demo", "bob").is_ok());
+        assert_eq!(policy.check_subdomain("alice-api", "bob").unwrap_err().code(), tonic::Code::PermissionDenied);
+        // `*`不能跨越多级子域名
+        for subdomain in ["bob-x.alice-api", "bob-", "bob-a_b", "", "bob-ä", &format!("bob-{}", "a".repeat(60))] {
+            assert!(policy.check_subdomain(subdomain, "bob").is_err(), "{}", subdomain);
+        }
+
+        // 生成的随机子域名符合第一个格式
+        let random = policy.random_subdomain("bob").unwrap();
+        assert!(random.starts_with("bob-") && policy.check_subdomain(&random, "bob").is_ok());
+        assert!(self::policy(&[]).random_subdomain("bob").is_none());
+        assert!(self::policy(&[]).check_subdomain("any", "bob").is_ok());
+    }
+
+    #[test]
+    fn dns_labels() {
+        for label in ["a", "bob", "bob-api", "0day", &"a".repeat(63)] {
+            assert!(is_dns_label(label), "{}", label);
+        }
+        for label in ["", "-bob", "bob-", "a.b", "a_b", "a b", "a*", &"a".repeat(64)] {
+            assert!(!is_dns_label(label), "{}", label);
+        }
+    }
+
+    #[test]
+    fn port_ranges() {
+        assert_eq!(parse_port_range("18000-19000"), Some((18000, 19000)));
+        assert_eq!(parse_port_range(" 20000 - 20000 "), Some((20000, 20000)));
+        for range in ["", "18000", "18000-", "-19000", "19000-18000", "1-70000", "a-b", "1-2-3"] {
+            assert_eq!(parse_port_range(range), None, "{}", range);
+        }
+
+        let mut policy = policy(&[]);
+        policy.ports = Some("19000-18000".to_string());
+        assert!(policy.validate().is_err());
+        policy.ports = Some("20000-20100".to_string());
+        assert!(policy.validate().is_ok());
+        assert_eq!(policy.port_range(), Some((20000, 20100)));
+    }
+
+    #[test]
+    fn user_tunnels_are_counted_per_user() {
+        let tunnels = UserTunnels::default();
+        let first = tunnels.acquire("bob", 2).unwrap();
+        let second = tunnels.acquire("bob", 2).unwrap();
+        assert!(tunnels.acquire("bob", 2).is_none());
+        let alice = tunnels.acquire("alice", 1).unwrap();
+        assert!(tunnels.acquire("alice", 1).is_none());
+
+        // 关闭一个隧道后可以再开，全部关闭后不留记录
+        drop(first);
+        let third = tunnels.acquire("bob", 2).unwrap();
+        assert!(tunnels.acquire("bob", 2).is_none());
+        drop((second, third, alice));
+        assert!(tunnels.0.is_empty());
+    }
+}
@@ -0,0 +1,357 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde_derive::Deserialize;
+use tokio::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use crate::server::config::Core;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+// 地址加上常见的TLV扩展远小于这个长度，避免按对端声明的长度分配内存
+const V2_MAX_LEN: usize = 1024;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An IP network in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`.
+/// A bare address is treated as a single-host network.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| format!("invalid cidr: {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.trim().parse::<u8>().ok().filter(|p| *p <= max).ok_or(format!("invalid cidr: {}", s))?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Decodes PROXY protocol (v1 and v2) headers sent by an L4 load balancer
+/// in front of the public listeners, so the visitor's real address can be
+/// used instead of the balancer's.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyProtocol {
+    enabled: bool,
+    trusted: Vec<Cidr>,
+}
+
+impl ProxyProtocol {
+    pub fn new(core: &Core) -> Self {
+        ProxyProtocol { enabled: core.proxy_protocol, trusted: core.trusted_proxies.clone() }
+    }
+
+    // 只接受可信代理发来的头部，否则访客可以伪造地址
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|c| c.contains(ip))
+    }
+
+    /// Returns the visitor address for a freshly accepted stream. When the peer
+    /// is a trusted proxy the PROXY header is consumed from the stream, so what
+    /// remains is the original payload.
+    pub async fn accept(&self, stream: &mut TcpStream, peer: SocketAddr) -> io::Result<SocketAddr> {
+        if !self.enabled || !self.is_trusted(peer.ip()) {
+            return Ok(peer);
+        }
+
+        let addr = timeout(HEADER_TIMEOUT, read_header(stream)).await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out reading PROXY header"))??;
+        Ok(addr.unwrap_or(peer))
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// 返回None表示头部未携带地址(v1 UNKNOWN / v2 LOCAL)，此时使用对端地址
+async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut prefix = [0u8; 6];
+    stream.read_exact(&mut prefix).await?;
+    if prefix == V1_PREFIX {
+        return read_v1(stream).await;
+    }
+    if prefix == V2_SIGNATURE[..6] {
+        return read_v2(stream).await;
+    }
+
+    Err(invalid("missing PROXY protocol header"))
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // v1头部以CRLF结尾，逐字节读取以免吞掉后续的业务数据
+    let mut line = Vec::with_capacity(V1_MAX_LEN);
+    line.extend_from_slice(V1_PREFIX);
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    parse_v1(&line[..line.len() - 2])
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("PROXY v1 header is not ascii"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, dst, sport, dport] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("invalid PROXY v1 source address"))?;
+            let dst: IpAddr = dst.parse().map_err(|_| invalid("invalid PROXY v1 destination address"))?;
+            // 地址必须和声明的协议族一致
+            if ip.is_ipv4() != (*family == "TCP4") || dst.is_ipv4() != ip.is_ipv4() {
+                return Err(invalid("PROXY v1 address does not match its family"));
+            }
+            let port: u16 = sport.parse().map_err(|_| invalid("invalid PROXY v1 source port"))?;
+            dport.parse::<u16>().map_err(|_| invalid("invalid PROXY v1 destination port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut head = [0u8; 10];
+    stream.read_exact(&mut head).await?;
+    if head[..6] != V2_SIGNATURE[6..] {
+        return Err(invalid("invalid PROXY v2 signature"));
+    }
+
+    let ver_cmd = head[6];
+    let family = head[7];
+    let len = u16::from_be_bytes([head[8], head[9]]) as usize;
+    if len > V2_MAX_LEN {
+        return Err(invalid("PROXY v2 header too long"));
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    parse_v2(ver_cmd & 0x0f, family, &body)
+}
+
+fn parse_v2(command: u8, family: u8, body: &[u8]) -> io::Result<Option<SocketAddr>> {
+    // LOCAL命令是代理自身发起的连接(如健康检查)
+    if command == 0x0 {
+        return Ok(None);
+    }
+    if command != 0x1 {
+        return Err(invalid("unsupported PROXY v2 command"));
+    }
+
+    // 地址块长度: IPv4为2*4+2*2，IPv6为2*16+2*2
+    let need = match family >> 4 {
+        0x1 => 12,
+        0x2 => 36,
+        _ => 0,
+    };
+    if body.len() < need {
+        return Err(invalid("truncated PROXY v2 address"));
+    }
+
+    match family >> 4 {
+        0x1 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        0x2 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        0x0 => Ok(None),
+        _ => Err(invalid("unsupported PROXY v2 address family")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+    use super::*;
+
+    fn v2(ver_cmd: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[ver_cmd, family]);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(body);
+        header
+    }
+
+    async fn read(data: &[u8]) -> io::Result<Option<SocketAddr>> {
+        let mut stream = data;
+        read_header(&mut stream).await
+    }
+
+    #[test]
+    fn parses_v1_lines() {
+        assert_eq!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443").unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443").unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(parse_v1(b"PROXY UNKNOWN").unwrap(), None);
+        assert_eq!(parse_v1(b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_v1_lines() {
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324").is_err());
+        assert!(parse_v1(b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443").is_err());
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443").is_err());
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 x").is_err());
+        assert!(parse_v1(b"PROXY TCP4 example.com 198.51.100.1 56324 443").is_err());
+        // 地址与协议族不一致
+        assert!(parse_v1(b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 443").is_err());
+        assert!(parse_v1(b"PROXY TCP6 192.0.2.1 198.51.100.1 56324 443").is_err());
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 2001:db8::2 56324 443").is_err());
+    }
+
+    #[test]
+    fn parses_v2_addresses() {
+        let body = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        assert_eq!(parse_v2(0x1, 0x11, &body).unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        body.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        body.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(parse_v2(0x1, 0x21, &body).unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        // LOCAL命令和UNSPEC协议族不携带地址
+        assert_eq!(parse_v2(0x0, 0x11, &[]).unwrap(), None);
+        assert_eq!(parse_v2(0x1, 0x00, &[]).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_bad_v2_headers() {
+        assert!(parse_v2(0x1, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04]).is_err());
+        assert!(parse_v2(0x1, 0x21, &[0; 35]).is_err());
+        assert!(parse_v2(0x2, 0x11, &[0; 12]).is_err());
+        assert!(parse_v2(0x1, 0x31, &[0; 216]).is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_headers_and_keeps_the_payload() {
+        let mut data = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n".to_vec();
+        let mut stream = data.as_slice();
+        assert_eq!(read_header(&mut stream).await.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        data = v2(0x21, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        data.extend_from_slice(b"payload");
+        let mut stream = data.as_slice();
+        assert_eq!(read_header(&mut stream).await.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"payload");
+
+        assert_eq!(read(&v2(0x20, 0x00, &[])).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_headers() {
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+        // 缺少CRLF或超过v1最大长度
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443").await.is_err());
+        let long = format!("PROXY UNKNOWN {}\r\n", "x".repeat(V1_MAX_LEN));
+        assert!(read(long.as_bytes()).await.is_err());
+
+        let mut bad_signature = v2(0x21, 0x11, &[0; 12]);
+        bad_signature[8] = b'X';
+        assert!(read(&bad_signature).await.is_err());
+        assert!(read(&v2(0x11, 0x11, &[0; 12])).await.is_err());
+        assert!(read(&v2(0x21, 0x11, &[0; V2_MAX_LEN + 1])).await.is_err());
+        // 声明的长度超过实际数据
+        let mut truncated = v2(0x21, 0x11, &[0; 12]);
+        truncated.truncate(20);
+        assert!(read(&truncated).await.is_err());
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        assert_eq!("10.0.0.0/8".parse::<Cidr>().unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!("192.0.2.1".parse::<Cidr>().unwrap().to_string(), "192.0.2.1/32");
+        assert_eq!("2001:db8::/32".parse::<Cidr>().unwrap().to_string(), "2001:db8::/32");
+        assert_eq!("::1".parse::<Cidr>().unwrap().to_string(), "::1/128");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn cidrs_contain_addresses() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let v4: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(v4.contains(ip("10.1.2.3")));
+        assert!(!v4.contains(ip("11.0.0.1")));
+        assert!(!v4.contains(ip("2001:db8::1")));
+        // 双栈监听时IPv4访客以映射地址出现
+        assert!(v4.contains(ip("::ffff:10.1.2.3")));
+        assert!(!v4.contains(ip("::ffff:11.0.0.1")));
+
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(ip("2001:db8:1::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+
+        let host: Cidr = "192.0.2.1".parse().unwrap();
+        assert!(host.contains(ip("192.0.2.1")));
+        assert!(!host.contains(ip("192.0.2.2")));
+
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("203.0.113.9")));
+        assert!("::/0".parse::<Cidr>().unwrap().contains(ip("2001:db8::1")));
+    }
+}
@@ -0,0 +1,348 @@
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use log::{debug, warn};
+use tokio::time::interval;
+use tonic::Status;
+use crate::server::config::Core;
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A token bucket refilled by `rate` tokens per minute, holding at most `rate` tokens.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u32) -> Self {
+        Bucket { tokens: rate as f64, updated: Instant::now() }
+    }
+
+    fn refill(&mut self, rate: u32) {
+        let now = Instant::now();
+        let added = now.duration_since(self.updated).as_secs_f64() * rate as f64 / 60.0;
+        self.tokens = (self.tokens + added).min(rate as f64);
+        self.updated = now;
+    }
+
+    /// Takes a token, or returns how long to wait for the next one.
+    fn take(&mut self, rate: u32) -> Result<(), Duration> {
+        self.refill(rate);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) * 60.0 / rate as f64))
+    }
+
+    /// Returns a token taken for an attempt that was rejected elsewhere.
+    fn refund(&mut self, rate: u32) {
+        self.tokens = (self.tokens + 1.0).min(rate as f64);
+    }
+
+    fn is_full(&mut self, rate: u32) -> bool {
+        self.refill(rate);
+        self.tokens >= rate as f64
+    }
+}
+
+/// Login attempts seen from one address.
+#[derive(Debug)]
+struct Attempts {
+    bucket: Bucket,
+    failures: u32,
+    last_failure: Option<Instant>,
+    locked_until: Option<Instant>,
+}
+
+impl Attempts {
+    fn new(limits: &LoginLimits) -> Self {
+        Attempts { bucket: Bucket::new(limits.rate), failures: 0, last_failure: None, locked_until: None }
+    }
+}
+
+/// Thresholds of the [`LoginLimiter`], taken from `Core`.
+#[derive(Debug, Clone)]
+pub struct LoginLimits {
+    /// Attempts per minute from one address, 0 disables.
+    pub rate: u32,
+    /// Attempts per minute from all addresses, 0 disables.
+    pub global_rate: u32,
+    /// Failed logins from one address before it is locked out, 0 disables lockouts.
+    pub max_failures: u32,
+    /// Duration of the first lockout, doubled with each further failure.
+    pub lockout: Duration,
+    /// Upper bound of a lockout, failures older than this are forgotten.
+    pub max_lockout: Duration,
+}
+
+impl From<&Core> for LoginLimits {
+    fn from(core: &Core) -> Self {
+        LoginLimits {
+            rate: core.login_rate_limit,
+            global_rate: core.login_global_rate_limit,
+            max_failures: core.login_max_failures,
+            lockout: Duration::from_secs(core.login_lockout),
+            max_lockout: Duration::from_secs(core.login_max_lockout),
+        }
+    }
+}
+
+/// Rate limits `Login` per source address and globally, and locks out
+/// addresses with too many failed logins for exponentially longer.
+#[derive(Debug)]
+pub struct LoginLimiter {
+    limits: parking_lot::RwLock<LoginLimits>,
+    global: parking_lot::Mutex<Bucket>,
+    addrs: DashMap<IpAddr, Attempts>,
+}
+
+impl LoginLimiter {
+    pub fn new(limits: LoginLimits) -> Self {
+        let global = parking_lot::Mutex::new(Bucket::new(limits.global_rate));
+        LoginLimiter { limits: parking_lot::RwLock::new(limits), global, addrs: Default::default() }
+    }
+
+    /// Applies new thresholds, the attempts seen so far are kept.
+    pub fn set_limits(&self, limits: LoginLimits) {
+        *self.limits.write() = limits;
+    }
+
+    /// Admits a login attempt from `addr`, or returns how long it has to wait.
+    pub fn check(&self, addr: Option<IpAddr>) -> Result<(), Duration> {
+        let limits = self.limits.read().clone();
+        let mut attempts = addr.map(source).map(|addr| (addr, self.addrs.entry(addr).or_insert_with(|| Attempts::new(&limits))));
+        if let Some((addr, attempts)) = attempts.as_mut() {
+            if let Some(remaining) = attempts.locked_until.and_then(|until| until.checked_duration_since(Instant::now())) {
+                debug!("login from {} rejected: locked out for {:?}", addr, remaining);
+                return Err(remaining);
+            }
+            if limits.rate > 0 {
+                if let Err(wait) = attempts.bucket.take(limits.rate) {
+                    debug!("login from {} rejected: rate limited", addr);
+                    return Err(wait);
+                }
+            }
+        }
+        if limits.global_rate > 0 {
+            if let Err(wait) = self.global.lock().take(limits.global_rate) {
+                debug!("login rejected: global rate limit of {} per minute reached", limits.global_rate);
+                // 未被处理的尝试不计入该地址的额度，否则全局限流时每个地址的额度都会被耗尽
+                if let Some((_, attempts)) = attempts.as_mut().filter(|_| limits.rate > 0) {
+                    attempts.bucket.refund(limits.rate);
+                }
+                return Err(wait);
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a failed login, locking the address out once it failed too often.
+    pub fn failed(&self, addr: Option<IpAddr>) {
+        let limits = self.limits.read().clone();
+        let addr = match addr.map(source) {
+            Some(addr) if limits.max_failures > 0 => addr,
+            _ => return,
+        };
+        let now = Instant::now();
+        let mut attempts = self.addrs.entry(addr).or_insert_with(|| Attempts::new(&limits));
+        // 距上次失败足够久则重新计数
+        if attempts.last_failure.is_some_and(|at| now.duration_since(at) >= limits.max_lockout) {
+            attempts.failures = 0;
+        }
+        attempts.failures += 1;
+        attempts.last_failure = Some(now);
+
+        if attempts.failures >= limits.max_failures {
+            let exponent = (attempts.failures - limits.max_failures).min(16);
+            let lockout = limits.lockout.saturating_mul(1 << exponent).min(limits.max_lockout);
+            attempts.locked_until = Some(now + lockout);
+            warn!("locked out {} for {:?} after {} failed logins", addr, lockout, attempts.failures);
+        }
+    }
+
+    /// Forgets the failures of an address after a successful login.
+    pub fn succeeded(&self, addr: Option<IpAddr>) {
+        if let Some(mut attempts) = addr.and_then(|addr| self.addrs.get_mut(&source(addr))) {
+            attempts.failures = 0;
+            attempts.last_failure = None;
+            attempts.locked_until = None;
+        }
+    }
+
+    // 只保留还有限制作用的记录
+    fn prune(&self) {
+        let limits = self.limits.read().clone();
+        let now = Instant::now();
+        self.addrs.retain(|_, attempts| {
+            let locked = attempts.locked_until.is_some_and(|until| until > now);
+            let failed = attempts.last_failure.is_some_and(|at| now.duration_since(at) < limits.max_lockout);
+            locked || failed || !attempts.bucket.is_full(limits.rate)
+        });
+    }
+}
+
+/// The status returned to a login rejected by the [`LoginLimiter`].
+pub(crate) fn too_many_attempts(wait: Duration) -> Status {
+    Status::resource_exhausted(format!("too many login attempts, retry in {}s", wait.as_secs() + 1))
+}
+
+// IPv6客户端通常拥有整个/64，按前缀计数
+fn source(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(_) => addr,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let s = v6.segments();
+                IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+            }
+        },
+    }
+}
+
+// 定期清理不再需要的登录记录
+pub async fn prune_login_attempts(limiter: Arc<LoginLimiter>) {
+    let mut ticker = interval(PRUNE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        limiter.prune();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use super::*;
+
+    fn limits(max_failures: u32) -> LoginLimits {
+        LoginLimits {
+            rate: 0,
+            global_rate: 0,
+            max_failures,
+            lockout: Duration::from_secs(10),
+            max_lockout: Duration::from_secs(40),
+        }
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    // 返回剩余的锁定时间，向上取整到秒
+    fn locked_for(limiter: &LoginLimiter, addr: Option<IpAddr>) -> Option<u64> {
+        limiter.check(addr).err().map(|wait| wait.as_secs_f64().ceil() as u64)
+    }
+
+    #[test]
+    fn bucket_holds_rate_tokens() {
+        let mut bucket = Bucket::new(60);
+        for _ in 0..60 {
+            assert!(bucket.take(60).is_ok());
+        }
+        // 每分钟60个，下一个最多等一秒
+        let wait = bucket.take(60).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1), "{:?}", wait);
+        assert!(!bucket.is_full(60));
+
+        // 一分钟后重新装满，但不超过容量
+        bucket.updated -= Duration::from_secs(120);
+        assert!(bucket.is_full(60));
+        assert!((bucket.tokens - 60.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn rate_limits_per_address_and_globally() {
+        let limiter = LoginLimiter::new(LoginLimits { rate: 2, global_rate: 3, ..limits(0) });
+        assert!(limiter.check(ip("10.0.0.1")).is_ok());
+        assert!(limiter.check(ip("10.0.0.1")).is_ok());
+        assert!(limiter.check(ip("10.0.0.1")).is_err());
+        assert!(limiter.check(ip("10.0.0.2")).is_ok());
+        // 全局额度用完后，新的地址也要等待
+        assert!(limiter.check(ip("10.0.0.3")).is_err());
+    }
+
+    #[test]
+    fn lockouts_double_up_to_the_maximum() {
+        let limiter = LoginLimiter::new(limits(3));
+        let addr = ip("10.0.0.1");
+        limiter.failed(addr);
+        limiter.failed(addr);
+        assert_eq!(locked_for(&limiter, addr), None);
+
+        let mut expected = Vec::new();
+        for _ in 0..4 {
+            limiter.failed(addr);
+            expected.push(locked_for(&limiter, addr));
+        }
+        assert_eq!(expected, vec![Some(10), Some(20), Some(40), Some(40)]);
+        assert_eq!(locked_for(&limiter, ip("10.0.0.2")), None);
+
+        // 登录成功后清除失败记录
+        limiter.succeeded(addr);
+        assert_eq!(locked_for(&limiter, addr), None);
+        limiter.failed(addr);
+        assert_eq!(locked_for(&limiter, addr), None);
+    }
+
+    #[test]
+    fn lockouts_can_be_disabled() {
+        let limiter = LoginLimiter::new(limits(0));
+        for _ in 0..10 {
+            limiter.failed(ip("10.0.0.1"));
+        }
+        assert!(limiter.check(ip("10.0.0.1")).is_ok());
+        // 没有来源地址时无法计数
+        limiter.failed(None);
+        assert!(limiter.check(None).is_ok());
+    }
+
+    #[test]
+    fn ipv6_addresses_count_per_prefix() {
+        assert_eq!(source(ip("2001:db8:1:2:3:4:5:6").unwrap()), ip("2001:db8:1:2::").unwrap());
+        assert_eq!(source(ip("::ffff:192.0.2.1").unwrap()), IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(source(ip("192.0.2.1").unwrap()), ip("192.0.2.1").unwrap());
+
+        // 同一个/64内换地址也逃不过锁定
+        let limiter = LoginLimiter::new(limits(1));
+        limiter.failed(ip("2001:db8::1"));
+        assert!(limiter.check(ip("2001:db8::ffff:1")).is_err());
+        assert!(limiter.check(ip("2001:db8:0:1::1")).is_ok());
+        limiter.failed(ip("::ffff:192.0.2.1"));
+        assert!(limiter.check(ip("192.0.2.1")).is_err());
+    }
+
+    #[test]
+    fn prune_keeps_only_active_records() {
+        let limits = LoginLimits { rate: 10, ..limits(3) };
+        let limiter = LoginLimiter::new(limits.clone());
+        limiter.failed(ip("10.0.0.1"));
+        limiter.check(ip("10.0.0.2")).unwrap();
+        limiter.addrs.insert(source(ip("10.0.0.3").unwrap()), Attempts::new(&limits));
+        limiter.prune();
+        // 有失败记录或令牌未满的地址保留，其余清除
+        assert!(limiter.addrs.contains_key(&ip("10.0.0.1").unwrap()));
+        assert!(limiter.addrs.contains_key(&ip("10.0.0.2").unwrap()));
+        assert!(!limiter.addrs.contains_key(&ip("10.0.0.3").unwrap()));
+    }
+
+    #[test]
+    fn globally_limited_attempts_keep_the_address_quota() {
+        let limiter = LoginLimiter::new(LoginLimits { rate: 2, global_rate: 1, ..limits(0) });
+        assert!(limiter.check(ip("192.0.2.1")).is_ok());
+        // 全局额度用完后被拒绝的尝试不消耗地址的额度
+        for _ in 0..5 {
+            assert!(limiter.check(ip("192.0.2.2")).is_err());
+        }
+        limiter.set_limits(LoginLimits { rate: 2, global_rate: 0, ..limits(0) });
+        assert!(limiter.check(ip("192.0.2.2")).is_ok());
+        assert!(limiter.check(ip("192.0.2.2")).is_ok());
+        assert!(limiter.check(ip("192.0.2.2")).is_err());
+        assert!(limiter.check(ip("192.0.2.1")).is_ok());
+        assert!(limiter.check(ip("192.0.2.1")).is_err());
+    }
+}
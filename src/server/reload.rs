@@ -0,0 +1,180 @@
+use std::fmt;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use crate::server::{Authenticator, Config};
+
+/// A value replaced as a whole on reload, readers keep the version they loaded.
+#[derive(Debug)]
+pub struct Shared<T: ?Sized>(Arc<RwLock<Arc<T>>>);
+
+impl<T: ?Sized> Shared<T> {
+    pub fn new(value: Arc<T>) -> Self {
+        Shared(Arc::new(RwLock::new(value)))
+    }
+
+    pub fn load(&self) -> Arc<T> {
+        self.0.read().clone()
+    }
+
+    pub fn store(&self, value: Arc<T>) {
+        *self.0.write() = value;
+    }
+}
+
+impl<T: ?Sized> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Shared(self.0.clone())
+    }
+}
+
+/// The config and the authenticators built from it, replaced together so that no
+/// login or tunnel sees the tokens of one version with the policies of another.
+#[derive(Debug)]
+pub struct Runtime {
+    pub cfg: Arc<Config>,
+    pub auth: Arc<dyn Authenticator>,
+}
+
+/// What a reload changed in the running server.
+#[derive(Debug, Clone, Default)]
+pub struct ReloadSummary {
+    pub changes: Vec<String>,
+    /// Settings that changed in the file but only apply after a restart.
+    pub restart_required: Vec<String>,
+    /// Users removed or given a new token, their sessions are revoked.
+    pub revoked_users: Vec<String>,
+    pub revoked_sessions: usize,
+}
+
+impl ReloadSummary {
+    pub fn diff(old: &Config, new: &Config) -> Self {
+        let mut summary = ReloadSummary::default();
+        // 按用户名排序，日志中的顺序保持稳定
+        let mut users: Vec<_> = new.tokens.iter().collect();
+        users.sort_by_key(|(username, _)| *username);
+        for (username, entry) in users {
+            match old.tokens.get(username) {
+                None => summary.changes.push(format!("added user {}", username)),
+                Some(old_entry) if old_entry != entry => {
+                    summary.changes.push(format!("changed token of user {}", username));
+                    summary.revoked_users.push(username.clone());
+                }
+                _ => {}
+            }
+        }
+        let mut removed: Vec<_> = old.tokens.keys().filter(|u| !new.tokens.contains_key(*u)).collect();
+        removed.sort();
+        for username in removed {
+            summary.changes.push(format!("removed user {}", username));
+            summary.revoked_users.push(username.clone());
+        }
+        summary.revoked_users.sort();
+
+        let (o, n) = (&old.core, &new.core);
+        let reloaded = [
+            ("core.auth_method", o.auth_method != n.auth_method),
+            ("core.allow_ports", o.allow_ports != n.allow_ports),
+            ("core.idle_timeout", o.idle_timeout != n.idle_timeout),
+            ("core.heartbeat_interval", o.heartbeat_interval != n.heartbeat_interval),
+            ("core.heartbeat_timeout", o.heartbeat_timeout != n.heartbeat_timeout),
+            ("core.login_*", o.login_rate_limit != n.login_rate_limit
+                || o.login_global_rate_limit != n.login_global_rate_limit
+                || o.login_max_failures != n.login_max_failures
+                || o.login_lockout != n.login_lockout
+                || o.login_max_lockout != n.login_max_lockout),
+            ("http.default_domain", old.http.default_domain != new.http.default_domain),
+            ("http.unavailable_page", old.http.unavailable_page != new.http.unavailable_page),
+            ("http.response_timeout", old.http.response_timeout != new.http.response_timeout),
+            ("[oidc]", old.oidc != new.oidc),
+            ("[htpasswd]", old.htpasswd != new.htpasswd),
+            ("[callout]", old.callout != new.callout),
+            ("[[policies]]", old.policies != new.policies),
+        ];
+        summary.changes.extend(reloaded.iter().filter(|(_, changed)| *changed).map(|(name, _)| format!("changed {}", name)));
+
+        let restart = [
+            ("core.debug", o.debug != n.debug),
+            ("core.bind_addr", o.bind_addr != n.bind_addr),
+            ("core.proxy_protocol", o.proxy_protocol != n.proxy_protocol || o.trusted_proxies != n.trusted_proxies),
+            ("core.session_ttl", o.session_ttl != n.session_ttl),
+            ("core.session_idle_timeout", o.session_idle_timeout != n.session_idle_timeout),
+            ("http.bind_addr", old.http.bind_addr != new.http.bind_addr),
+            ("[tls]", old.tls != new.tls),
+            ("[admin]", old.admin != new.admin),
+            ("[[session_keys]]", old.session_keys != new.session_keys),
+        ];
+        summary.restart_required.extend(restart.iter().filter(|(_, changed)| *changed).map(|(name, _)| name.to_string()));
+        summary
+    }
+}
+
+impl fmt::Display for ReloadSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            write!(f, "no changes")?;
+        } else {
+            write!(f, "{}", self.changes.join(", "))?;
+        }
+        if self.revoked_sessions > 0 {
+            write!(f, "; revoked {} sessions", self.revoked_sessions)?;
+        }
+        if !self.restart_required.is_empty() {
+            write!(f, "; restart to apply {}", self.restart_required.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::server::TokenEntry;
+    use super::*;
+
+    fn config() -> Config {
+        serde_json::from_value(json!({
+            "core": { "debug": false, "bind_addr": "127.0.0.1:8422", "auth_method": "token", "allow_ports": "18000-18100" },
+            "http": { "bind_addr": "127.0.0.1:8423", "default_domain": "localtest.me" },
+            "tokens": { "alice": "rslocald_Pz3xLq8VwN5tKe2M", "bob": "rslocald_Wq8ZkP3nVx7Lm2Rt" },
+        })).unwrap()
+    }
+
+    #[test]
+    fn unchanged_config_has_no_changes() {
+        let summary = ReloadSummary::diff(&config(), &config());
+        assert!(summary.changes.is_empty() && summary.restart_required.is_empty() && summary.revoked_users.is_empty());
+        assert_eq!(summary.to_string(), "no changes");
+    }
+
+    #[test]
+    fn token_changes_revoke_their_users() {
+        let old = config();
+        let mut new = config();
+        new.tokens.remove("alice");
+        new.tokens.insert("bob".to_string(), TokenEntry::Token("rslocald_Hn4cT9yBq2XsLd7K".to_string()));
+        new.tokens.insert("carol".to_string(), TokenEntry::Token("rslocald_Cr7yHn2QmZ4xTb9W".to_string()));
+        let mut summary = ReloadSummary::diff(&old, &new);
+        assert_eq!(summary.changes, ["changed token of user bob", "added user carol", "removed user alice"]);
+        assert_eq!(summary.revoked_users, ["alice", "bob"]);
+        summary.revoked_sessions = 3;
+        assert_eq!(summary.to_string(), "changed token of user bob, added user carol, removed user alice; revoked 3 sessions");
+    }
+
+    #[test]
+    fn settings_are_applied_or_need_a_restart() {
+        let old = config();
+        let mut new = config();
+        new.core.allow_ports = "20000-20100".to_string();
+        new.core.login_lockout = 120;
+        new.http.response_timeout = 5;
+        new.policies.push(Default::default());
+        new.core.bind_addr = "127.0.0.1:9422".to_string();
+        new.core.session_ttl = 3600;
+        let summary = ReloadSummary::diff(&old, &new);
+        assert_eq!(summary.changes, ["changed core.allow_ports", "changed core.login_*", "changed http.response_timeout", "changed [[policies]]"]);
+        assert_eq!(summary.restart_required, ["core.bind_addr", "core.session_ttl"]);
+        assert!(summary.revoked_users.is_empty());
+        assert_eq!(summary.to_string(), "changed core.allow_ports, changed core.login_*, changed http.response_timeout, changed [[policies]]; restart to apply core.bind_addr, core.session_ttl");
+    }
+}
@@ -0,0 +1,579 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use jsonwebtoken::{Algorithm, decode, decode_header, DecodingKey, encode, EncodingKey, get_current_timestamp, Header, Validation};
+use log::{debug, info};
+use serde_derive::{Deserialize, Serialize};
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+use crate::random_string;
+use crate::server::config::SessionKey;
+use crate::server::Identity;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+// 签名的会话无法在各实例间统一注销，必须有过期时间
+const DEFAULT_SIGNED_TTL: Duration = Duration::from_secs(86400);
+
+/// Why a session ended, announced to the tunnels opened with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    /// The session outlived its TTL or was not used for too long, the client may log in again.
+    Expired,
+    /// The user logged out or an operator revoked the session.
+    Revoked,
+}
+
+#[derive(Debug)]
+struct SessionState {
+    id: String,
+    identity: Identity,
+    created_at: u64,
+    expires_at: Option<u64>,
+    expires: Option<Instant>,
+    last_used: parking_lot::Mutex<Instant>,
+    tunnels: AtomicUsize,
+    ended: CancellationToken,
+    reason: parking_lot::Mutex<Option<SessionEnd>>,
+}
+
+impl SessionState {
+    fn new(id: String, identity: Identity, created_at: u64, expires_at: Option<u64>) -> Self {
+        let now = get_current_timestamp();
+        SessionState {
+            id,
+            identity,
+            created_at,
+            expires_at,
+            expires: expires_at.map(|at| Instant::now() + Duration::from_secs(at.saturating_sub(now))),
+            last_used: parking_lot::Mutex::new(Instant::now()),
+            tunnels: AtomicUsize::new(0),
+            ended: CancellationToken::new(),
+            reason: Default::default(),
+        }
+    }
+
+    fn end(&self, reason: SessionEnd) {
+        self.reason.lock().get_or_insert(reason);
+        self.ended.cancel();
+    }
+}
+
+/// A logged in session, handed to the RPCs it authorizes.
+#[derive(Debug, Clone)]
+pub struct Session(Arc<SessionState>);
+
+impl Session {
+    pub fn identity(&self) -> &Identity {
+        &self.0.identity
+    }
+
+    /// Names the session in the admin API, unlike the session token it grants nothing.
+    pub fn id(&self) -> &str {
+        &self.0.id
+    }
+
+    /// Unix timestamp of the login.
+    pub fn created_at(&self) -> u64 {
+        self.0.created_at
+    }
+
+    /// Unix timestamp the session expires at regardless of use.
+    pub fn expires_at(&self) -> Option<u64> {
+        self.0.expires_at
+    }
+
+    /// Number of tunnels opened with the session.
+    pub fn tunnels(&self) -> usize {
+        self.0.tunnels.load(Ordering::Relaxed)
+    }
+
+    /// Completes once the session expired or was revoked.
+    pub async fn ended(&self) -> SessionEnd {
+        self.0.ended.cancelled().await;
+        self.0.reason.lock().unwrap_or(SessionEnd::Revoked)
+    }
+
+    /// Keeps the session from going idle while the returned guard is alive.
+    pub fn tunnel(&self) -> SessionTunnel {
+        self.0.tunnels.fetch_add(1, Ordering::Relaxed);
+        SessionTunnel(self.0.clone())
+    }
+}
+
+/// A tunnel opened with a session, see [`Session::tunnel`].
+#[derive(Debug)]
+pub struct SessionTunnel(Arc<SessionState>);
+
+impl Drop for SessionTunnel {
+    fn drop(&mut self) {
+        *self.0.last_used.lock() = Instant::now();
+        self.0.tunnels.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionClaims {
+    sid: String,
+    sub: String,
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(default)]
+    attrs: HashMap<String, String>,
+    #[serde(default)]
+    scopes: Option<Vec<String>>,
+    iat: u64,
+    /// `iat` in milliseconds, missing in tokens issued by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    iat_ms: Option<u64>,
+    exp: u64,
+}
+
+impl SessionClaims {
+    fn issued_at_ms(&self) -> u64 {
+        self.iat_ms.unwrap_or(self.iat * 1000)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Signs and verifies stateless session tokens with HMAC-SHA256.
+pub struct SessionSigner {
+    keys: Vec<(String, EncodingKey, DecodingKey)>,
+}
+
+impl SessionSigner {
+    /// The first key signs new sessions, all of them are accepted. Returns
+    /// `None` without keys.
+    pub fn new(keys: &[SessionKey]) -> Option<Self> {
+        if keys.is_empty() {
+            return None;
+        }
+        let keys = keys.iter()
+            .map(|k| (k.id.clone(), EncodingKey::from_secret(k.secret.as_bytes()), DecodingKey::from_secret(k.secret.as_bytes())))
+            .collect();
+        Some(SessionSigner { keys })
+    }
+
+    fn sign(&self, claims: &SessionClaims) -> String {
+        let (kid, key, _) = &self.keys[0];
+        let header = Header { kid: Some(kid.clone()), ..Header::new(Algorithm::HS256) };
+        encode(&header, claims, key).expect("HS256 signing never fails")
+    }
+
+    fn verify(&self, token: &str) -> Option<SessionClaims> {
+        let kid = decode_header(token).ok()?.kid?;
+        let (_, _, key) = self.keys.iter().find(|(id, _, _)| *id == kid)?;
+        match decode::<SessionClaims>(token, key, &Validation::new(Algorithm::HS256)) {
+            Ok(data) => Some(data.claims),
+            Err(e) => {
+                debug!("invalid session token: {}", e);
+                None
+            }
+        }
+    }
+}
+
+// 不输出密钥
+impl fmt::Debug for SessionSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ids: Vec<&str> = self.keys.iter().map(|(id, _, _)| id.as_str()).collect();
+        f.debug_struct("SessionSigner").field("keys", &ids).finish()
+    }
+}
+
+/// Sessions handed out by `Login`, expiring after a TTL or when unused.
+///
+/// With a [`SessionSigner`] the session id is a signed token that every server
+/// sharing the keys accepts, and only the tunnels are tracked in memory. Logout
+/// and revocation then only apply to the instance handling them.
+#[derive(Debug)]
+pub struct SessionStore {
+    sessions: DashMap<String, Session>,
+    ttl: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    signer: Option<SessionSigner>,
+    // 签名会话的注销记录，保留到对应的令牌过期
+    revoked_sessions: DashMap<String, u64>,
+    // 撤销用户的时间(毫秒)，秒级精度会误伤同一秒内重新登录的会话
+    revoked_users: DashMap<String, u64>,
+}
+
+impl SessionStore {
+    /// A zero `ttl` or `idle_timeout` disables that limit.
+    pub fn new(ttl: Duration, idle_timeout: Duration) -> Self {
+        let limit = |d: Duration| if d.is_zero() { None } else { Some(d) };
+        SessionStore {
+            sessions: Default::default(),
+            ttl: limit(ttl),
+            idle_timeout: limit(idle_timeout),
+            signer: None,
+            revoked_sessions: Default::default(),
+            revoked_users: Default::default(),
+        }
+    }
+
+    /// Issues signed session tokens instead of in-memory ids, the TTL defaults to a day.
+    pub fn with_signer(mut self, signer: SessionSigner) -> Self {
+        self.ttl.get_or_insert(DEFAULT_SIGNED_TTL);
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Starts a session for `identity` and returns its id.
+    pub fn create(&self, identity: Identity) -> String {
+        // 会话不能比登录凭据活得更久
+        let now = get_current_timestamp();
+        let lifetime = match (self.ttl, identity.expires_at) {
+            (Some(ttl), Some(at)) => Some(ttl.min(Duration::from_secs(at.saturating_sub(now)))),
+            (ttl, at) => ttl.or_else(|| at.map(|at| Duration::from_secs(at.saturating_sub(now)))),
+        };
+        let expires_at = lifetime.map(|lifetime| now + lifetime.as_secs());
+        let (key, id, session_id) = match (&self.signer, lifetime) {
+            (Some(signer), Some(lifetime)) => {
+                let sid = random_string(32);
+                // 签发时间必须晚于该用户最近一次被撤销的时间
+                let revoked_at = self.revoked_users.get(&identity.username).map(|at| *at);
+                let iat_ms = revoked_at.map_or(now_ms(), |at| now_ms().max(at + 1));
+                let token = signer.sign(&SessionClaims {
+                    sid: sid.clone(),
+                    sub: identity.username.clone(),
+                    groups: identity.groups.clone(),
+                    attrs: identity.attributes.clone(),
+                    scopes: identity.scopes.clone(),
+                    iat: now,
+                    iat_ms: Some(iat_ms),
+                    exp: now + lifetime.as_secs(),
+                });
+                (sid.clone(), sid, token)
+            }
+            _ => {
+                let session_id = random_string(128);
+                (session_id.clone(), random_string(16), session_id)
+            }
+        };
+        self.sessions.insert(key, Session(Arc::new(SessionState::new(id, identity, now, expires_at))));
+        session_id
+    }
+
+    /// Returns the session if it is still valid and marks it as used.
+    pub fn get(&self, session_id: &str) -> Option<Session> {
+        let (key, session) = match &self.signer {
+            None => (session_id.to_string(), self.sessions.get(session_id)?.clone()),
+            Some(signer) => {
+                // 其他实例签发或重启前签发的会话，第一次出现时登记以跟踪隧道
+                let claims = self.verify(signer, session_id)?;
+                let session = self.sessions.entry(claims.sid.clone()).or_insert_with(|| {
+                    let identity = Identity {
+                        username: claims.sub,
+                        groups: claims.groups,
+                        attributes: claims.attrs,
+                        scopes: claims.scopes,
+                        expires_at: Some(claims.exp),
+                    };
+                    Session(Arc::new(SessionState::new(claims.sid.clone(), identity, claims.iat, Some(claims.exp))))
+                }).clone();
+                (claims.sid, session)
+            }
+        };
+        if self.expired(&session) {
+            self.end(&key, SessionEnd::Expired);
+            return None;
+        }
+        *session.0.last_used.lock() = Instant::now();
+        Some(session)
+    }
+
+    /// Ends one session, returns false if it did not exist.
+    pub fn remove(&self, session_id: &str) -> bool {
+        let signer = match &self.signer {
+            Some(signer) => signer,
+            None => return self.end(session_id, SessionEnd::Revoked),
+        };
+        match self.verify(signer, session_id) {
+            Some(claims) => {
+                self.revoked_sessions.insert(claims.sid.clone(), claims.exp);
+                self.end(&claims.sid, SessionEnd::Revoked);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Ends the session named `id` by [`Session::id`], returns false if it did not exist.
+    pub fn revoke(&self, id: &str) -> bool {
+        let key = match self.sessions.iter().find(|s| s.id() == id) {
+            Some(s) => s.key().clone(),
+            None => return false,
+        };
+        self.end(&key, SessionEnd::Revoked)
+    }
+
+    /// The sessions known to this server, oldest first.
+    pub fn list(&self) -> Vec<Session> {
+        let mut sessions: Vec<Session> = self.sessions.iter().map(|s| s.value().clone()).collect();
+        sessions.sort_by_key(Session::created_at);
+        sessions
+    }
+
+    /// Ends every session of `username` and tears down their tunnels.
+    pub fn revoke_user(&self, username: &str) -> usize {
+        if self.signer.is_some() {
+            self.revoked_users.insert(username.to_string(), now_ms());
+        }
+        let ids: Vec<String> = self.sessions.iter()
+            .filter(|s| s.identity().username == username)
+            .map(|s| s.key().clone())
+            .collect();
+        ids.iter().filter(|id| self.end(id, SessionEnd::Revoked)).count()
+    }
+
+    fn verify(&self, signer: &SessionSigner, token: &str) -> Option<SessionClaims> {
+        let claims = signer.verify(token)?;
+        if self.revoked_sessions.contains_key(&claims.sid) {
+            return None;
+        }
+        // 撤销之前签发的会话都不再接受
+        if self.revoked_users.get(&claims.sub).is_some_and(|at| claims.issued_at_ms() <= *at) {
+            return None;
+        }
+        Some(claims)
+    }
+
+    fn end(&self, key: &str, reason: SessionEnd) -> bool {
+        match self.sessions.remove(key) {
+            Some((_, session)) => {
+                // 签名的令牌本身仍然有效，需要记下来，否则空闲过期的会话下次使用时又会被重新登记
+                if let (Some(_), Some(exp)) = (&self.signer, session.expires_at()) {
+                    self.revoked_sessions.insert(key.to_string(), exp);
+                }
+                info!("session of user {} ended: {:?}", session.identity().username, reason);
+                session.0.end(reason);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // 隧道仍在使用的会话不算空闲，TTL到期则无论如何都会结束
+    fn expired(&self, session: &Session) -> bool {
+        let state = &session.0;
+        let over_ttl = state.expires.is_some_and(|at| Instant::now() >= at);
+        let idle = self.idle_timeout.map(|timeout| {
+            state.tunnels.load(Ordering::Relaxed) == 0 && state.last_used.lock().elapsed() >= timeout
+        }).unwrap_or(false);
+        over_ttl || idle
+    }
+
+    fn sweep(&self) {
+        let expired: Vec<String> = self.sessions.iter()
+            .filter(|s| self.expired(s.value()))
+            .map(|s| s.key().clone())
+            .collect();
+        for key in expired {
+            self.end(&key, SessionEnd::Expired);
+        }
+
+        // 令牌过期后注销记录也就没用了
+        let now = get_current_timestamp();
+        let ttl = self.ttl.map_or(0, |ttl| ttl.as_secs());
+        self.revoked_sessions.retain(|_, exp| *exp > now);
+        self.revoked_users.retain(|_, at| *at / 1000 + ttl > now);
+    }
+}
+
+// 定期清理过期的会话，并结束这些会话上的隧道
+pub async fn expire_sessions(store: Arc<SessionStore>) {
+    let mut ticker = interval(SWEEP_INTERVAL);
+    loop {
+        ticker.tick().await;
+        store.sweep();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use super::*;
+
+    fn key(id: &str) -> SessionKey {
+        SessionKey { id: id.to_string(), secret: format!("{:0<32}", id) }
+    }
+
+    fn claims(iat: u64, exp: u64) -> SessionClaims {
+        SessionClaims {
+            sid: random_string(32),
+            sub: "bob".to_string(),
+            groups: vec!["dev".to_string()],
+            attrs: Default::default(),
+            scopes: Some(vec!["tunnel:http".to_string()]),
+            iat,
+            iat_ms: None,
+            exp,
+        }
+    }
+
+    fn signed_store(idle_timeout: Duration) -> SessionStore {
+        SessionStore::new(Duration::from_secs(3600), idle_timeout)
+            .with_signer(SessionSigner::new(&[key("k1")]).unwrap())
+    }
+
+    #[test]
+    fn signed_tokens_round_trip() {
+        let signer = SessionSigner::new(&[key("k1")]).unwrap();
+        let now = get_current_timestamp();
+        let token = signer.sign(&claims(now, now + 60));
+        let verified = signer.verify(&token).unwrap();
+        assert_eq!(verified.sub, "bob");
+        assert_eq!(verified.groups, vec!["dev"]);
+        assert_eq!(verified.scopes, Some(vec!["tunnel:http".to_string()]));
+
+        // 篡改过的令牌、同名但密钥不同的令牌都不接受
+        let mut tampered = token.clone();
+        tampered.insert(token.rfind('.').unwrap() + 1, 'x');
+        assert!(signer.verify(&tampered).is_none());
+        let forged = SessionSigner::new(&[SessionKey { id: "k1".to_string(), secret: "x".repeat(32) }]).unwrap();
+        assert!(signer.verify(&forged.sign(&claims(now, now + 60))).is_none());
+        assert!(signer.verify("not a token").is_none());
+    }
+
+    #[test]
+    fn rotated_keys_keep_accepting_old_tokens() {
+        let now = get_current_timestamp();
+        let old = SessionSigner::new(&[key("k1")]).unwrap();
+        let token = old.sign(&claims(now, now + 60));
+
+        // 新密钥放在前面签发，旧密钥签发的令牌仍然有效
+        let rotated = SessionSigner::new(&[key("k2"), key("k1")]).unwrap();
+        assert!(rotated.verify(&token).is_some());
+        assert!(rotated.verify(&rotated.sign(&claims(now, now + 60))).is_some());
+        assert!(old.verify(&rotated.sign(&claims(now, now + 60))).is_none());
+
+        // 旧密钥移除后就不再接受
+        let retired = SessionSigner::new(&[key("k2")]).unwrap();
+        assert!(retired.verify(&token).is_none());
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let signer = SessionSigner::new(&[key("k1")]).unwrap();
+        // 留出校验的默认宽限时间
+        let now = get_current_timestamp();
+        assert!(signer.verify(&signer.sign(&claims(now - 3600, now - 120))).is_none());
+
+        let store = SessionStore::new(Duration::from_secs(3600), Duration::ZERO)
+            .with_signer(SessionSigner::new(&[key("k1")]).unwrap());
+        assert!(store.get(&signer.sign(&claims(now - 3600, now - 120))).is_none());
+    }
+
+    #[test]
+    fn sessions_end_after_the_ttl() {
+        for store in [
+            SessionStore::new(Duration::from_secs(1), Duration::ZERO),
+            SessionStore::new(Duration::from_secs(1), Duration::ZERO).with_signer(SessionSigner::new(&[key("k1")]).unwrap()),
+        ] {
+            let token = store.create(Identity::new("bob"));
+            let session = store.get(&token).unwrap();
+            let _tunnel = session.tunnel();
+            std::thread::sleep(Duration::from_millis(1100));
+            // 即使还有隧道，TTL到期也会结束
+            store.sweep();
+            assert!(store.get(&token).is_none());
+            assert_eq!(session.ended().now_or_never(), Some(SessionEnd::Expired));
+        }
+    }
+
+    #[test]
+    fn idle_sessions_end() {
+        for store in [
+            SessionStore::new(Duration::ZERO, Duration::from_millis(50)),
+            signed_store(Duration::from_millis(50)),
+        ] {
+            let token = store.create(Identity::new("bob"));
+            let session = store.get(&token).unwrap();
+
+            // 有隧道时不算空闲
+            let tunnel = session.tunnel();
+            std::thread::sleep(Duration::from_millis(80));
+            store.sweep();
+            assert!(store.get(&token).is_some());
+            drop(tunnel);
+
+            std::thread::sleep(Duration::from_millis(80));
+            store.sweep();
+            assert_eq!(session.ended().now_or_never(), Some(SessionEnd::Expired));
+            // 签名的令牌仍然有效，但不能再次登记
+            assert!(store.get(&token).is_none());
+            assert!(store.list().is_empty());
+        }
+    }
+
+    #[test]
+    fn revoked_sessions_are_rejected() {
+        for store in [SessionStore::new(Duration::ZERO, Duration::ZERO), signed_store(Duration::ZERO)] {
+            let token = store.create(Identity::new("bob"));
+            let session = store.get(&token).unwrap();
+            assert!(store.remove(&token));
+            assert!(store.get(&token).is_none());
+            assert_eq!(session.ended().now_or_never(), Some(SessionEnd::Revoked));
+
+            let token = store.create(Identity::new("bob"));
+            let id = store.get(&token).unwrap().id().to_string();
+            assert!(store.revoke(&id));
+            assert!(!store.revoke(&id));
+            assert!(store.get(&token).is_none());
+
+            let bob = [store.create(Identity::new("bob")), store.create(Identity::new("bob"))];
+            let alice = store.create(Identity::new("alice"));
+            assert_eq!(store.revoke_user("bob"), 2);
+            assert!(bob.iter().all(|token| store.get(token).is_none()));
+            assert!(store.get(&alice).is_some());
+        }
+    }
+
+    #[test]
+    fn signed_sessions_are_revoked_before_this_instance_saw_them() {
+        // 另一个实例签发、本实例还没见过的会话
+        let other = signed_store(Duration::ZERO);
+        let store = signed_store(Duration::ZERO);
+        let token = other.create(Identity::new("bob"));
+        assert!(store.remove(&token));
+        assert!(store.get(&token).is_none());
+
+        let token = other.create(Identity::new("alice"));
+        store.revoke_user("alice");
+        assert!(store.get(&token).is_none());
+        assert!(other.get(&token).is_some());
+    }
+
+    #[test]
+    fn sessions_after_a_revocation_in_the_same_second_are_kept() {
+        let store = signed_store(Duration::ZERO);
+        let signer = SessionSigner::new(&[key("k1")]).unwrap();
+        let old = store.create(Identity::new("bob"));
+        assert_eq!(store.revoke_user("bob"), 1);
+        let revoked_at = *store.revoked_users.get("bob").unwrap();
+
+        // 撤销之后立即重新登录，通常与撤销在同一秒内
+        let new = store.create(Identity::new("bob"));
+        assert!(store.get(&old).is_none());
+        assert!(store.get(&new).is_some());
+
+        let at = |iat_ms: u64| {
+            let mut claims = claims(iat_ms / 1000, iat_ms / 1000 + 60);
+            claims.iat_ms = Some(iat_ms);
+            signer.sign(&claims)
+        };
+        assert!(store.get(&at(revoked_at)).is_none());
+        assert!(store.get(&at(revoked_at - 1)).is_none());
+        assert!(store.get(&at(revoked_at + 1)).is_some());
+        // 旧版本签发的令牌只有秒级时间，同一秒内签发的视为已撤销
+        assert!(store.get(&signer.sign(&claims(revoked_at / 1000, revoked_at / 1000 + 60))).is_none());
+        assert!(store.get(&signer.sign(&claims(revoked_at / 1000 + 1, revoked_at / 1000 + 60))).is_some());
+    }
+}
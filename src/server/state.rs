@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{ErrorKind, Write};
+
+use anyhow::Context;
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use crate::server::config::TokenEntry;
+use crate::server::Config;
+
+/// A subdomain or tcp port only `username` may listen on.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Reservation {
+    pub username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subdomain: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+}
+
+impl fmt::Display for Reservation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.subdomain, self.port) {
+            (Some(subdomain), _) => write!(f, "subdomain {}", subdomain),
+            (None, Some(port)) => write!(f, "port {}", port),
+            (None, None) => write!(f, "nothing"),
+        }
+    }
+}
+
+/// Tokens and reservations managed through the admin API, saved to `admin.state_file`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AdminState {
+    pub tokens: HashMap<String, TokenEntry>,
+    pub reservations: Vec<Reservation>,
+}
+
+impl AdminState {
+    /// Reads the state saved at `path`, a missing file is an empty state.
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).with_context(|| format!("invalid state file {}", path)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(AdminState::default()),
+            Err(e) => Err(e).with_context(|| format!("failed to read state file {}", path)),
+        }
+    }
+
+    /// Replaces the file at `path`, only its owner may read it.
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        // 先写临时文件再改名，中途失败不会留下半个文件
+        let tmp = format!("{}.tmp", path);
+        // 权限只在创建时设置，上次失败留下的临时文件要先删掉
+        match fs::remove_file(&tmp) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e).with_context(|| format!("failed to remove {}", tmp)),
+            _ => {}
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&tmp).with_context(|| format!("failed to write state file {}", tmp))?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path).with_context(|| format!("failed to replace state file {}", path))?;
+        Ok(())
+    }
+
+    /// The `[tokens]` of `cfg` together with the managed tokens, the config wins for users in both.
+    pub fn tokens(&self, cfg: &Config) -> HashMap<String, TokenEntry> {
+        let mut tokens = cfg.tokens.clone();
+        for (username, entry) in &self.tokens {
+            if tokens.contains_key(username) {
+                warn!("managed token of user {} is ignored, the config file defines one", username);
+                continue;
+            }
+            tokens.insert(username.clone(), entry.clone());
+        }
+        tokens
+    }
+
+    pub fn subdomain_owner(&self, subdomain: &str) -> Option<&str> {
+        self.reservations.iter().find(|r| r.subdomain.as_deref() == Some(subdomain)).map(|r| r.username.as_str())
+    }
+
+    pub fn port_owner(&self, port: u16) -> Option<&str> {
+        self.reservations.iter().find(|r| r.port == Some(port)).map(|r| r.username.as_str())
+    }
+
+    /// The ports reserved for `username`.
+    pub fn ports_of<'a>(&'a self, username: &'a str) -> impl Iterator<Item=u16> + 'a {
+        self.reservations.iter().filter(move |r| r.username == username).filter_map(|r| r.port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::random_string;
+    use crate::server::TokenConfig;
+    use super::*;
+
+    fn temp_path() -> String {
+        std::env::temp_dir().join(format!("rslocald-state-{}.json", random_string(8))).to_str().unwrap().to_string()
+    }
+
+    fn state() -> AdminState {
+        let mut state = AdminState::default();
+        state.tokens.insert("ci".to_string(), TokenEntry::Detailed(TokenConfig {
+            token: "sha256:5cf0bf7a60940c8bd57225f8f5cd9eb8eeb097c5e9bc564c2f147d48b0f02c80".to_string(),
+            expires: Some("2999-01-01".to_string()),
+            description: Some("build runner".to_string()),
+            scopes: Some(vec!["tunnel:tcp".to_string()]),
+        }));
+        state.reservations.push(Reservation { username: "alice".to_string(), subdomain: Some("demo".to_string()), port: None });
+        state.reservations.push(Reservation { username: "bob".to_string(), subdomain: None, port: Some(18001) });
+        state
+    }
+
+    #[test]
+    fn saved_state_loads_back() {
+        let path = temp_path();
+        assert!(AdminState::load(&path).unwrap().reservations.is_empty());
+
+        state().save(&path).unwrap();
+        let loaded = AdminState::load(&path).unwrap();
+        assert_eq!(loaded.tokens, state().tokens);
+        assert_eq!(loaded.reservations, state().reservations);
+        assert_eq!(loaded.subdomain_owner("demo"), Some("alice"));
+        assert_eq!(loaded.port_owner(18001), Some("bob"));
+        assert_eq!(loaded.ports_of("bob").collect::<Vec<_>>(), [18001]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saved_state_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = temp_path();
+        let mode = |path: &str| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        // 覆盖已有的文件和上次留下的临时文件时也不放宽权限
+        fs::write(&path, "{}").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let tmp = format!("{}.tmp", path);
+        fs::write(&tmp, "").unwrap();
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o644)).unwrap();
+        state().save(&path).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert!(fs::metadata(&tmp).is_err());
+
+        state().save(&path).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_state_is_an_error() {
+        let path = temp_path();
+        fs::write(&path, "not json").unwrap();
+        assert!(AdminState::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,8 +1,10 @@
 use std::collections::HashMap;
 use std::error::Error;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use parking_lot::Mutex;
 use futures::FutureExt;
 use tokio::{io};
@@ -11,26 +13,33 @@ use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, oneshot};
 use tokio::sync::mpsc::{Sender};
 use tokio_util::sync::PollSender;
-use url::Url;
 use crate::{random_string, RxReader, TxWriter};
-use crate::server::{Connection, Payload, XData};
+use crate::server::{Connection, entrypoint_addr, Payload, ProxyProtocol, XData};
+use crate::server::api::Visitor;
+
+/// Pause after a failed accept, e.g. when out of file descriptors, instead of retrying at once.
+pub(crate) const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
 
 #[derive(Clone)]
 pub struct TcpServer {
+    proxy_protocol: ProxyProtocol,
     listeners: Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>,
 }
 
 impl TcpServer {
-    pub fn new() -> Self {
-        TcpServer { listeners: Default::default() }
+    pub fn new(proxy_protocol: ProxyProtocol) -> Self {
+        TcpServer { proxy_protocol, listeners: Default::default() }
     }
 
-    pub async fn event_handler(&mut self, pl: Payload) {
-        let u = Url::parse(pl.entrypoint.as_str()).unwrap();
-        let mut addr = u.host_str().unwrap().to_string();
-        if let Some(port) = u.port() {
-            addr = format!("{}:{}", addr, port);
-        }
+    pub async fn event_handler(&mut self, mut pl: Payload) {
+        let addr = match entrypoint_addr(&pl.entrypoint) {
+            Some(addr) => addr,
+            None => {
+                warn!("invalid entrypoint: {}", pl.entrypoint);
+                pl.opened(Err(format!("invalid entrypoint {}", pl.entrypoint)));
+                return;
+            }
+        };
 
         if pl.tx.is_closed() {
             debug!("stop tcp-server");
@@ -39,52 +48,77 @@ impl TcpServer {
         }
 
         debug!("start tcp-server");
-        self.start(addr, pl.tx).await;
+        let result = self.start(addr, pl.tx.clone()).await;
+        pl.opened(result);
     }
 
-    async fn start(&mut self, addr: String, conn_tx: Sender<Connection>) {
+    // 先绑定端口，失败时不登记监听
+    async fn start(&mut self, addr: String, conn_tx: Sender<Connection>) -> Result<(), String> {
+        let listener = match TcpListener::bind(&addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("tcp server failed to listen on {}: {}", addr, e);
+                return Err(format!("failed to listen on {}: {}", addr, e));
+            }
+        };
+        info!("tcp server listening on {}", addr);
         let (tx, rx) = oneshot::channel();
         self.listeners.lock().insert(addr.clone(), tx); // 存储tx供stop调用
 
+        let proxy_protocol = self.proxy_protocol.clone();
         tokio::spawn(async move {
-            info!("tcp server listening on {}", addr.clone());
-            let listener = TcpListener::bind(addr).await.unwrap();
             tokio::select! {
             _ = async {
                 loop {
                     let conn_txc = conn_tx.clone();
-                    let (stream, _) = listener.accept().await.unwrap();
-                    tokio::spawn(async move { process(stream, conn_txc).await });
+                    let proxy_protocol = proxy_protocol.clone();
+                    let (mut stream, peer) = match listener.accept().await {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            warn!("tcp server {} failed to accept: {}", addr, e);
+                            tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                            continue;
+                        }
+                    };
+                    tokio::spawn(async move {
+                        match proxy_protocol.accept(&mut stream, peer).await {
+                            Ok(remote_addr) => process(stream, remote_addr, conn_txc).await,
+                            Err(e) => warn!("drop connection from {}: {}", peer, e),
+                        }
+                    });
                 }
-
-                // Help the rust type inferencer out
-                Ok::<_, io::Error>(())
             } => {}
             _ = rx => {
                 debug!("tcp server terminating");
             }
         }
         });
+        Ok(())
     }
 
     fn stop(&self, addr: String) {
         let mut mg = self.listeners.lock();
-        let tx = mg.remove(addr.as_str()).unwrap();
-        tx.send(()).unwrap();
-        info!("tcp server {} closed.", addr);
+        if let Some(tx) = mg.remove(addr.as_str()) {
+            let _ = tx.send(());
+            info!("tcp server {} closed.", addr);
+        }
     }
 }
 
-async fn process(stream: TcpStream, conn_tx: Sender<Connection>) {
-    info!("processing stream from: {:?}", stream.peer_addr());
+async fn process(stream: TcpStream, remote_addr: SocketAddr, conn_tx: Sender<Connection>) {
+    info!("processing stream from: {}", remote_addr);
     // 准备接收Response用的channel, 等待客户端接入
     let conn_id = random_string(32);
     let (tx, mut rx) = mpsc::channel(128);
-    conn_tx.send(Connection { id: conn_id.clone(), tx: tx.clone() }).await.unwrap(); // 通知Connection已就绪
-    if let XData::TX(dtx) = rx.recv().await.unwrap() {
-        let rx_reader = RxReader { rx };
-        let tx_writer = TxWriter { conn_id, tx: PollSender::new(dtx) };
-        tokio::spawn(transfer(stream, rx_reader, tx_writer).map(|r| {
+    let visitor = Visitor { remote_addr: remote_addr.to_string(), ..Default::default() };
+    // 通知Connection已就绪，隧道已关闭时直接断开访客
+    if conn_tx.send(Connection { id: conn_id.clone(), tx, visitor }).await.is_err() {
+        return;
+    }
+    if let Some(XData::TX(dtx)) = rx.recv().await {
+        let rx_reader = RxReader::new(rx);
+        let tx_writer = TxWriter { conn_id, tx: PollSender::new(dtx.clone()) };
+        tokio::spawn(transfer(stream, rx_reader, tx_writer, dtx).map(|r| {
             debug!("transfer map: {:?}", r);
             if let Err(e) = r {
                 error!("Failed to transfer; error={}", e);
@@ -93,7 +127,7 @@ async fn process(stream: TcpStream, conn_tx: Sender<Connection>) {
     }
 }
 
-async fn transfer(mut inbound: TcpStream, mut ro: RxReader<XData>, mut wo: TxWriter<Vec<u8>>) -> Result<(), Box<dyn Error>> {
+async fn transfer(mut inbound: TcpStream, mut ro: RxReader<XData>, mut wo: TxWriter<XData>, ctl: Sender<XData>) -> Result<(), Box<dyn Error>> {
     debug!("transfer");
     let (mut ri, mut wi) = inbound.split();
 
@@ -111,6 +145,43 @@ async fn transfer(mut inbound: TcpStream, mut ro: RxReader<XData>, mut wo: TxWri
         wi.shutdown().await
     };
 
-    tokio::try_join!(client_to_server, server_to_client)?;
+    if let Err(e) = tokio::try_join!(client_to_server, server_to_client) {
+        // 任意一端异常断开时，两端都以RST中止连接
+        let _ = ctl.send(XData::Reset).await;
+        let _ = inbound.set_linger(Some(Duration::ZERO));
+        return Err(e.into());
+    }
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(addr: &str) -> (Payload, oneshot::Receiver<Result<(), String>>, mpsc::Receiver<Connection>) {
+        let (tx, conns) = mpsc::channel(1);
+        let (ready, opened) = oneshot::channel();
+        (Payload { tx, entrypoint: format!("tcp://{}", addr), ready: Some(ready) }, opened, conns)
+    }
+
+    #[tokio::test]
+    async fn failed_bind_is_reported_and_not_registered() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = taken.local_addr().unwrap().to_string();
+        let mut server = TcpServer::new(ProxyProtocol::default());
+        let (pl, opened, _conns) = payload(&addr);
+        server.event_handler(pl).await;
+        assert!(opened.await.unwrap().is_err());
+        assert!(server.listeners.lock().is_empty());
+
+        // 端口释放后可以正常监听，关闭后注销
+        drop(taken);
+        let (pl, opened, conns) = payload(&addr);
+        server.event_handler(pl).await;
+        assert_eq!(opened.await.unwrap(), Ok(()));
+        assert!(server.listeners.lock().contains_key(&addr));
+        drop(conns);
+        let (closed, _) = mpsc::channel(1);
+        server.event_handler(Payload { tx: closed, entrypoint: format!("tcp://{}", addr), ready: None }).await;
+        assert!(server.listeners.lock().is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{anyhow, bail};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use crate::random_string;
+
+const SHA256_PREFIX: &str = "sha256:";
+const ARGON2_PREFIX: &str = "$argon2";
+const GENERATED_PREFIX: &str = "rslocald_";
+
+const MIN_TOKEN_LEN: usize = 16;
+const MIN_DISTINCT_CHARS: usize = 8;
+
+/// Algorithms accepted by `rslocald token hash`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Argon2,
+}
+
+impl std::str::FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "argon2" => Ok(HashAlgorithm::Argon2),
+            _ => Err(format!("unknown algorithm {}, expected sha256 or argon2", s)),
+        }
+    }
+}
+
+/// A token entry of the `[tokens]` table.
+///
+/// Entries are either `sha256:<hex>`, an argon2 PHC string (`$argon2id$...`)
+/// or, for compatibility, the plaintext token.
+#[derive(Debug, Clone)]
+pub enum StoredToken {
+    Sha256([u8; 32]),
+    Argon2(String),
+}
+
+impl StoredToken {
+    /// Parses an entry, returning whether it was stored in plaintext.
+    pub fn parse(entry: &str) -> anyhow::Result<(Self, bool)> {
+        if let Some(hex) = entry.strip_prefix(SHA256_PREFIX) {
+            let digest = decode_hex(hex).ok_or_else(|| anyhow!("invalid sha256 digest"))?;
+            return Ok((StoredToken::Sha256(digest), false));
+        }
+        if entry.starts_with(ARGON2_PREFIX) {
+            let hash = PasswordHash::new(entry).map_err(|e| anyhow!("invalid argon2 hash: {}", e))?;
+            // 缺少盐或摘要的条目永远不会匹配
+            if hash.salt.is_none() || hash.hash.is_none() {
+                bail!("invalid argon2 hash: missing salt or digest");
+            }
+            return Ok((StoredToken::Argon2(entry.to_string()), false));
+        }
+
+        check_strength(entry)?;
+        // 明文也只保存摘要，比较时长度固定
+        Ok((StoredToken::Sha256(sha256(entry)), true))
+    }
+
+    /// Compares in constant time, `digest` is the sha256 of the presented token.
+    pub fn verify(&self, token: &str, digest: &[u8; 32]) -> bool {
+        match self {
+            StoredToken::Sha256(expected) => expected.ct_eq(digest).into(),
+            StoredToken::Argon2(hash) => PasswordHash::new(hash)
+                .map(|hash| Argon2::default().verify_password(token.as_bytes(), &hash).is_ok())
+                .unwrap_or(false),
+        }
+    }
+
+    pub fn is_argon2(&self) -> bool {
+        matches!(self, StoredToken::Argon2(_))
+    }
+}
+
+/// Returns the `[tokens]` entry for `token`.
+pub fn hash_token(token: &str, algorithm: HashAlgorithm) -> anyhow::Result<String> {
+    check_strength(token)?;
+    match algorithm {
+        HashAlgorithm::Sha256 => Ok(format!("{}{}", SHA256_PREFIX, encode_hex(&sha256(token)))),
+        HashAlgorithm::Argon2 => {
+            let salt = SaltString::generate(&mut OsRng);
+            let hash = Argon2::default().hash_password(token.as_bytes(), &salt)
+                .map_err(|e| anyhow!("failed to hash token: {}", e))?;
+            Ok(hash.to_string())
+        }
+    }
+}
+
+/// Generates a random token.
+pub fn generate_token() -> String {
+    format!("{}{}", GENERATED_PREFIX, random_string(32))
+}
+
+/// Rejects tokens that are short or made of only a few distinct characters.
+pub fn check_strength(token: &str) -> anyhow::Result<()> {
+    if token.chars().count() < MIN_TOKEN_LEN {
+        bail!("weak token: at least {} characters are required", MIN_TOKEN_LEN);
+    }
+    if token.chars().collect::<HashSet<_>>().len() < MIN_DISTINCT_CHARS {
+        bail!("weak token: at least {} distinct characters are required", MIN_DISTINCT_CHARS);
+    }
+    Ok(())
+}
+
+/// Parses the expiry of a token entry to a unix timestamp, dates mean midnight UTC.
+pub fn parse_expiry(expires: &str) -> anyhow::Result<u64> {
+    let expires = expires.trim();
+    let time = if expires.len() == 10 {
+        humantime::parse_rfc3339_weak(&format!("{} 00:00:00", expires))
+    } else {
+        humantime::parse_rfc3339_weak(expires)
+    }.map_err(|e| anyhow!("invalid expiry {}: {}", expires, e))?;
+    Ok(time.duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+pub(crate) fn sha256(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+fn decode_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "rslocald_Pz3xLq8VwN5tKe2M";
+
+    fn verify(entry: &str, token: &str) -> bool {
+        let (stored, _) = StoredToken::parse(entry).unwrap();
+        stored.verify(token, &sha256(token))
+    }
+
+    #[test]
+    fn sha256_entries_are_hex_digests() {
+        let entry = hash_token(TOKEN, HashAlgorithm::Sha256).unwrap();
+        assert_eq!(entry, "sha256:5cf0bf7a60940c8bd57225f8f5cd9eb8eeb097c5e9bc564c2f147d48b0f02c80");
+        assert_eq!(decode_hex(&entry[SHA256_PREFIX.len()..]), Some(sha256(TOKEN)));
+        assert!(verify(&entry, TOKEN));
+        assert!(!verify(&entry, "rslocald_Wq8ZkP3nVx7Lm2Rt"));
+    }
+
+    #[test]
+    fn argon2_entries_are_salted() {
+        let entry = hash_token(TOKEN, HashAlgorithm::Argon2).unwrap();
+        assert!(entry.starts_with("$argon2id$"));
+        assert_ne!(entry, hash_token(TOKEN, HashAlgorithm::Argon2).unwrap());
+        assert!(StoredToken::parse(&entry).unwrap().0.is_argon2());
+        assert!(verify(&entry, TOKEN));
+        assert!(!verify(&entry, "rslocald_Wq8ZkP3nVx7Lm2Rt"));
+    }
+
+    #[test]
+    fn plaintext_entries_are_flagged() {
+        let (stored, plaintext) = StoredToken::parse(TOKEN).unwrap();
+        assert!(plaintext && !stored.is_argon2());
+        assert!(!StoredToken::parse(&hash_token(TOKEN, HashAlgorithm::Sha256).unwrap()).unwrap().1);
+        assert!(verify(TOKEN, TOKEN));
+    }
+
+    #[test]
+    fn malformed_entries_are_rejected() {
+        assert!(StoredToken::parse("sha256:5cf0").is_err());
+        assert!(StoredToken::parse(&format!("sha256:{}", "zz".repeat(32))).is_err());
+        assert!(StoredToken::parse("$argon2id$v=19$m=19456,t=2,p=1").is_err());
+    }
+
+    #[test]
+    fn weak_tokens_are_rejected() {
+        assert!(check_strength("rslocald_short").is_err());
+        assert!(check_strength("abababababababababab").is_err());
+        assert!(check_strength(TOKEN).is_ok());
+        assert!(check_strength(&generate_token()).is_ok());
+        assert!(hash_token("abababababababababab", HashAlgorithm::Sha256).is_err());
+    }
+
+    #[test]
+    fn expiries_are_utc() {
+        assert_eq!(parse_expiry("2024-07-01").unwrap(), 1719792000);
+        assert_eq!(parse_expiry("2024-07-01T12:00:00Z").unwrap(), 1719835200);
+        assert!(parse_expiry("tomorrow").is_err());
+    }
+
+    #[test]
+    fn algorithms_parse() {
+        assert_eq!("sha256".parse(), Ok(HashAlgorithm::Sha256));
+        assert_eq!("argon2".parse(), Ok(HashAlgorithm::Argon2));
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+    }
+}
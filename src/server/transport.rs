@@ -1,19 +1,51 @@
+use bytes::Bytes;
+use url::Url;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use crate::server::api::Visitor;
 
-#[derive(Debug, Clone)]
+/// Opens an entrypoint, or closes it once `tx` is closed.
+#[derive(Debug)]
 pub struct Payload {
     pub tx: Sender<Connection>,
     pub entrypoint: String,
+    /// Told whether the entrypoint could be opened, `None` when closing it.
+    pub ready: Option<oneshot::Sender<Result<(), String>>>,
+}
+
+impl Payload {
+    // 对方已不再等待时忽略
+    pub(crate) fn opened(&mut self, result: Result<(), String>) {
+        if let Some(ready) = self.ready.take() {
+            let _ = ready.send(result);
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Connection {
     pub(crate) id: String,
     pub(crate) tx: Sender<XData>,
+    pub(crate) visitor: Visitor,
 }
 
 #[derive(Debug, Clone)]
 pub enum XData {
-    TX(Sender<Vec<u8>>),
-    Data(Vec<u8>),
-}
\ No newline at end of file
+    TX(Sender<XData>),
+    Data(Bytes),
+    /// The peer closed its write half, no more data follows.
+    Eof,
+    /// The peer aborted the connection.
+    Reset,
+    /// The peer failed to serve the connection.
+    Error(String),
+}
+/// Returns the `host:port` part of an entrypoint such as `tcp://0.0.0.0:18000`.
+pub(crate) fn entrypoint_addr(entrypoint: &str) -> Option<String> {
+    let u = Url::parse(entrypoint).ok()?;
+    let host = u.host_str()?;
+    match u.port() {
+        Some(port) => Some(format!("{}:{}", host, port)),
+        None => Some(host.to_string()),
+    }
+}
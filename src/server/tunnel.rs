@@ -1,54 +1,259 @@
 use std::sync::Arc;
+use std::time::Duration;
 
-use log::{debug, info};
-use tokio::sync::mpsc;
+use hyper::server::conn::Http;
+use log::{debug, info, warn};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
 use tokio::sync::mpsc::{Sender};
-use tonic::transport::Server;
-use crate::server::{Config, HttpServer, MakeHttpServer, Payload, RSLServer, RSLUser, TcpServer};
+use tokio::time::interval;
+use anyhow::Context;
+use tonic::Status;
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use crate::server::{AdminState, Authenticator, Chain, Config, expire_sessions, HttpServer, LoginLimiter, LoginLimits, prune_login_attempts, ReloadSummary, Runtime, SessionSigner, SessionStore, Shared, METRICS, Payload, ProxyProtocol, RSLAdmin, RSLServer, RSLUser, TcpServer, TunnelRegistry};
+use crate::server::config::TLSConfig;
+use crate::server::tcp::ACCEPT_RETRY_DELAY;
+use crate::server::api::admin_server::AdminServer;
 use crate::server::api::tunnel_server::TunnelServer;
 use crate::server::api::user_server::UserServer;
 
+const METRICS_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
 pub struct Tunnel {
-    cfg: Config,
+    runtime: Shared<Runtime>,
+    config_file: Option<String>,
 
+    proxy_protocol: ProxyProtocol,
     tcp_server: TcpServer,
     http_server: HttpServer,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    limiter: Arc<LoginLimiter>,
+    sessions: Arc<SessionStore>,
+    tunnels: TunnelRegistry,
+    state: Shared<AdminState>,
+    state_file: Option<String>,
+    state_lock: Arc<parking_lot::Mutex<()>>,
 }
 
 impl Tunnel {
     pub fn new(cfg: Config) -> Self {
         let http_cfg = cfg.http.clone();
+        let proxy_protocol = ProxyProtocol::new(&cfg.core);
+        let mut sessions = SessionStore::new(
+            Duration::from_secs(cfg.core.session_ttl),
+            Duration::from_secs(cfg.core.session_idle_timeout),
+        );
+        if let Some(signer) = SessionSigner::new(&cfg.session_keys) {
+            sessions = sessions.with_signer(signer);
+        }
+        let limiter = LoginLimiter::new(LoginLimits::from(&cfg.core));
+        let state_file = cfg.admin.as_ref().and_then(|admin| admin.state_file.clone());
         Tunnel {
-            cfg,
-            tcp_server: TcpServer::new(),
+            runtime: Shared::new(Arc::new(Runtime { cfg: Arc::new(cfg), auth: Arc::new(Chain::new()) })),
+            config_file: None,
+            tcp_server: TcpServer::new(proxy_protocol.clone()),
             http_server: HttpServer::new(http_cfg),
+            proxy_protocol,
+            authenticator: None,
+            limiter: Arc::new(limiter),
+            sessions: Arc::new(sessions),
+            tunnels: Default::default(),
+            state: Shared::new(Arc::new(AdminState::default())),
+            state_file,
+            state_lock: Default::default(),
+        }
+    }
+
+    /// Re-reads `name` on the admin `Reload` call and, on Unix, on SIGHUP.
+    pub fn with_config_file(mut self, name: impl Into<String>) -> Self {
+        self.config_file = Some(name.into());
+        self
+    }
+
+    /// The config currently in effect.
+    pub fn config(&self) -> Arc<Config> {
+        self.runtime.load().cfg.clone()
+    }
+
+    /// The login sessions, e.g. to revoke those of a user who was offboarded.
+    pub fn sessions(&self) -> Arc<SessionStore> {
+        self.sessions.clone()
+    }
+
+    /// The tunnels being served.
+    pub fn tunnels(&self) -> TunnelRegistry {
+        self.tunnels.clone()
+    }
+
+    /// The tokens and reservations managed through the admin API.
+    pub fn state(&self) -> Arc<AdminState> {
+        self.state.load()
+    }
+
+    pub(crate) fn auth(&self) -> Arc<dyn Authenticator> {
+        self.runtime.load().auth.clone()
+    }
+
+    pub(crate) fn limiter(&self) -> Arc<LoginLimiter> {
+        self.limiter.clone()
+    }
+
+    /// Authenticates logins with `auth` instead of the methods listed in `core.auth_method`.
+    pub fn with_authenticator(mut self, auth: impl Authenticator + 'static) -> Self {
+        let auth: Arc<dyn Authenticator> = Arc::new(auth);
+        self.runtime.store(Arc::new(Runtime { cfg: self.config(), auth: auth.clone() }));
+        self.authenticator = Some(auth);
+        self
+    }
+
+    /// Re-reads the config file and applies tokens, policies and HTTP settings without
+    /// dropping tunnels. The running config is kept if the new one is invalid, sessions
+    /// of removed users or users given a new token are revoked.
+    pub async fn reload(&self) -> anyhow::Result<ReloadSummary> {
+        // 读取配置文件和构建认证链都会阻塞
+        let tunnel = self.clone();
+        tokio::task::spawn_blocking(move || tunnel.reload_blocking()).await?
+    }
+
+    fn reload_blocking(&self) -> anyhow::Result<ReloadSummary> {
+        let name = self.config_file.as_deref().context("the server was started without a config file")?;
+        let cfg = Arc::new(Config::new(name)?);
+        let _guard = self.state_lock.lock();
+        // 先构建好全部组件，出错时正在运行的配置不受影响
+        let auth = self.build_auth(&cfg, &self.state.load())?;
+        let mut summary = ReloadSummary::diff(&self.config(), &cfg);
+
+        self.runtime.store(Arc::new(Runtime { cfg: cfg.clone(), auth }));
+        self.limiter.set_limits(LoginLimits::from(&cfg.core));
+        self.http_server.inner.reload(cfg.http.clone());
+        for username in &summary.revoked_users {
+            summary.revoked_sessions += self.sessions.revoke_user(username);
+        }
+        Ok(summary)
+    }
+
+    /// Applies `change` to the tokens and reservations managed through the admin API and
+    /// saves them to `admin.state_file`. Nothing changes if `change` or saving fails.
+    #[allow(clippy::result_large_err)]
+    pub(crate) async fn update_state<R: Send + 'static>(&self, change: impl FnOnce(&mut AdminState) -> Result<R, Status> + Send + 'static) -> Result<R, Status> {
+        // 保存时要同步文件，重建认证链也可能读文件
+        let tunnel = self.clone();
+        tokio::task::spawn_blocking(move || tunnel.update_state_blocking(change)).await
+            .map_err(|e| Status::internal(e.to_string()))?
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn update_state_blocking<R>(&self, change: impl FnOnce(&mut AdminState) -> Result<R, Status>) -> Result<R, Status> {
+        let _guard = self.state_lock.lock();
+        let runtime = self.runtime.load();
+        let old = self.state.load();
+        let mut state = (*old).clone();
+        let result = change(&mut state)?;
+        // 只有令牌变化时才需要重建认证链
+        let auth = if state.tokens != old.tokens {
+            Some(self.build_auth(&runtime.cfg, &state).map_err(|e| Status::invalid_argument(format!("{:#}", e)))?)
+        } else {
+            None
+        };
+        if let Some(path) = &self.state_file {
+            state.save(path).map_err(|e| Status::internal(format!("{:#}", e)))?;
+        }
+
+        if let Some(auth) = auth {
+            self.runtime.store(Arc::new(Runtime { cfg: runtime.cfg.clone(), auth }));
+        }
+        self.state.store(Arc::new(state));
+        Ok(result)
+    }
+
+    // 配置文件中的令牌加上通过管理接口创建的令牌
+    fn build_auth(&self, cfg: &Config, state: &AdminState) -> anyhow::Result<Arc<dyn Authenticator>> {
+        if let Some(auth) = &self.authenticator {
+            return Ok(auth.clone());
         }
+        let mut cfg = cfg.clone();
+        cfg.tokens = state.tokens(&cfg);
+        Ok(Arc::new(Chain::from_config(&cfg)?))
     }
 
-    fn start_http_svc(&self) {
+    // 收到SIGHUP时重新加载配置，其他平台只能通过管理接口
+    #[cfg(unix)]
+    async fn reload_on_hangup(self) {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                warn!("failed to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            match self.reload().await {
+                Ok(summary) => info!("config reloaded: {}", summary),
+                Err(e) => warn!("config reload rejected, keeping the running config: {:#}", e),
+            }
+        }
+    }
+
+    // 先绑定端口，失败时直接从start返回
+    async fn start_http_svc(&self) -> anyhow::Result<()> {
         debug!("start http-server");
-        let cfg = self.cfg.clone();
-        let http_server_inner = Arc::clone(&self.http_server.inner);
+        let cfg = self.config();
+        let listener = TcpListener::bind(&cfg.http.bind_addr).await
+            .with_context(|| format!("http server failed to listen on {}", cfg.http.bind_addr))?;
+        info!("http server listening on //{}", cfg.http.bind_addr);
+        let http_server = self.http_server.clone();
+        let proxy_protocol = self.proxy_protocol.clone();
         tokio::spawn(async move {
-            let addr = cfg.http.bind_addr.parse().unwrap();
-            let server = hyper::Server::bind(&addr)
-                .http1_preserve_header_case(true)
-                .http1_title_case_headers(true)
-                .serve(MakeHttpServer { http_server: HttpServer { inner: http_server_inner } });
-
-            info!("http server listening on //{}", addr);
-            if let Err(e) = server.await {
-                eprintln!("server error: {}", e);
+            loop {
+                let (mut stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("http server failed to accept: {}", e);
+                        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+
+                let http_server = http_server.clone();
+                let proxy_protocol = proxy_protocol.clone();
+                tokio::spawn(async move {
+                    // 经过负载均衡时从PROXY协议头中取出访客的真实地址
+                    let remote_addr = match proxy_protocol.accept(&mut stream, peer).await {
+                        Ok(addr) => addr,
+                        Err(e) => {
+                            warn!("drop connection from {}: {}", peer, e);
+                            return;
+                        }
+                    };
+
+                    let result = Http::new()
+                        .http1_preserve_header_case(true)
+                        .http1_title_case_headers(true)
+                        .serve_connection(stream, http_server.with_remote_addr(remote_addr))
+                        .await;
+                    if let Err(e) = result {
+                        debug!("http connection from {} error: {}", remote_addr, e);
+                    }
+                });
             }
         });
+        Ok(())
     }
 
     pub async fn start(&self) -> anyhow::Result<()> {
+        if let Some(path) = &self.state_file {
+            let state = AdminState::load(path)?;
+            info!("loaded {} managed tokens and {} reservations from {}", state.tokens.len(), state.reservations.len(), path);
+            self.state.store(Arc::new(state));
+        }
+
         let (tx1, mut rx1) = mpsc::channel(128);
         let http_server_inner = self.http_server.inner.clone();
         tokio::spawn(async move {
             while let Some(msg) = rx1.recv().await {
-                http_server_inner.lock().await.event_handler(msg).await;
+                http_server_inner.event_handler(msg).await;
             }
         });
 
@@ -60,21 +265,74 @@ impl Tunnel {
             }
         });
 
-        self.start_http_svc();
-        self.run_grpc_svc(tx1, tx2).await
+        tokio::spawn(report_metrics());
+        tokio::spawn(expire_sessions(self.sessions.clone()));
+        tokio::spawn(prune_login_attempts(self.limiter.clone()));
+        #[cfg(unix)]
+        tokio::spawn(self.clone().reload_on_hangup());
+        self.start_http_svc().await?;
+        tokio::select! {
+            result = self.run_grpc_svc(tx1, tx2) => result,
+            result = self.run_admin_svc() => result,
+        }
     }
 
     async fn run_grpc_svc(&self, tx_http: Sender<Payload>, tx_tcp: Sender<Payload>) -> anyhow::Result<()> {
         debug!("run_grpc_svc");
-        let cfg = self.cfg.clone();
+        let cfg = self.config();
         let addr = cfg.core.bind_addr.parse()?;
-        let user = RSLUser::new(cfg.clone());
-        let tunnel = RSLServer::new(cfg, tx_tcp, tx_http);
+        {
+            let _guard = self.state_lock.lock();
+            let auth = self.build_auth(&cfg, &self.state.load())?;
+            self.runtime.store(Arc::new(Runtime { cfg: cfg.clone(), auth }));
+        }
+        let user = RSLUser::new(self.runtime.clone(), self.sessions.clone(), self.limiter.clone());
+        let (shutdown_tx, shutdown_rx) = watch::channel(String::new());
+        // HTTP/2层的keepalive，及时发现已经失联的连接
+        let keepalive = match cfg.core.heartbeat_interval {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        let keepalive_timeout = Some(Duration::from_secs(cfg.core.heartbeat_timeout));
+        let tunnel = RSLServer::new(self.runtime.clone(), self.state.clone(), self.tunnels.clone(), tx_tcp, tx_http, shutdown_rx);
+
+        let mut server = Server::builder();
+        if let Some(tls) = &cfg.tls {
+            server = server.tls_config(server_tls_config(tls, true)?)?;
+        }
 
         info!("grpc server listening on //{}", addr);
-        Server::builder()
+        server
+            .http2_keepalive_interval(keepalive)
+            .http2_keepalive_timeout(keepalive_timeout)
             .add_service(UserServer::new(user.clone()))
             .add_service(TunnelServer::with_interceptor(tunnel, user))
+            .serve_with_shutdown(addr, async move {
+                let _ = tokio::signal::ctrl_c().await;
+                info!("shutting down");
+                let _ = shutdown_tx.send("server shutting down".to_string());
+            })
+            .await?;
+        Ok(())
+    }
+
+    // 管理接口使用单独的端口，未配置时不启动
+    async fn run_admin_svc(&self) -> anyhow::Result<()> {
+        let cfg = self.config();
+        let admin = match &cfg.admin {
+            Some(admin) => admin,
+            None => return futures::future::pending().await,
+        };
+        let addr = admin.bind_addr.parse()?;
+        let mut server = Server::builder();
+        // 管理接口用令牌鉴权，不要求隧道客户端的证书，否则rslocald admin无法连接
+        if let Some(tls) = &cfg.tls {
+            server = server.tls_config(server_tls_config(tls, false)?)?;
+        }
+
+        info!("admin server listening on //{}", addr);
+        server
+            .add_service(AdminServer::new(RSLAdmin::new(self.clone())))
             .serve(addr)
             .await?;
         Ok(())
@@ -105,3 +363,56 @@ impl Tunnel {
 //     tx.send(v).await;
 // }
 
+
+// 定期输出运行指标，长期运行时可据此确认隧道和连接没有泄漏
+async fn report_metrics() {
+    let mut ticker = interval(METRICS_INTERVAL);
+    let mut last = None;
+    loop {
+        ticker.tick().await;
+        let snapshot = METRICS.snapshot();
+        if last != Some(snapshot) {
+            info!("metrics: {}", snapshot);
+            last = Some(snapshot);
+        }
+    }
+}
+
+fn server_tls_config(tls: &TLSConfig, verify_clients: bool) -> anyhow::Result<ServerTlsConfig> {
+    let cert = std::fs::read(&tls.cert).with_context(|| format!("failed to read tls cert {}", tls.cert))?;
+    let key = std::fs::read(&tls.key).with_context(|| format!("failed to read tls key {}", tls.key))?;
+    let mut config = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+    if let Some(path) = tls.client_ca.as_ref().filter(|_| verify_clients) {
+        let ca = std::fs::read(path).with_context(|| format!("failed to read tls client_ca {}", path))?;
+        config = config.client_ca_root(Certificate::from_pem(ca));
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::random_string;
+    use super::*;
+
+    #[tokio::test]
+    async fn invalid_config_keeps_the_running_one() {
+        let cfg: Config = serde_json::from_value(json!({
+            "core": { "debug": false, "bind_addr": "127.0.0.1:0", "auth_method": "token", "allow_ports": "18000-18100" },
+            "http": { "bind_addr": "127.0.0.1:0", "default_domain": "localtest.me" },
+            "tokens": {},
+        })).unwrap();
+        let path = std::env::temp_dir().join(format!("rslocald-{}.toml", random_string(8)));
+        std::fs::write(&path, "[core\nbind_addr = ").unwrap();
+        let tunnel = Tunnel::new(cfg).with_config_file(path.to_str().unwrap());
+        let runtime = tunnel.runtime.load();
+
+        assert!(tunnel.reload().await.is_err());
+        assert!(Arc::ptr_eq(&tunnel.runtime.load(), &runtime));
+        std::fs::remove_file(&path).unwrap();
+
+        // 没有配置文件时无法重新加载
+        let tunnel = Tunnel::new((*runtime.cfg).clone());
+        assert!(tunnel.reload().await.is_err());
+    }
+}